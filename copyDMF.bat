@@ -1,4 +1,4 @@
 cargo b
 format M: /V:MUSIC /Q
 cls
-target\debug\copy-asyncstd.exe D:\ミュージック M:\ミュージック %*
+target\debug\copy-asyncstd.exe copy D:\ミュージック M:\ミュージック %*
@@ -0,0 +1,604 @@
+/**
+ * Copyright (C) 2023 awk4j - https://ja.osdn.net/projects/awk4j/
+ * <p>
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ * <p>
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ * <p>
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+use chrono::{Local, NaiveDate, TimeZone};
+use clap::error::ErrorKind;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufWriter, Error, Read, Result, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zip::ZipArchive;
+
+use crate::cli;
+use crate::cli::Tar;
+use crate::iomod;
+use crate::messages::{tr, trf};
+use crate::thmod;
+
+/**
+ * Tarball - 入力フォルダを tar アーカイブに書き出す (tar <SRC> <ARCHIVE|->)
+ *
+ * フォルダを名前順にたどりながら1つずつ書き出す (エントリーを溜めないので、順序は毎回同じ)
+ * --exclude に一致するフォルダは中身ごと書き出さない
+ * 更新日時とパーミッションはそのまま、シンボリックリンクはたどらない
+ * 読めないエントリーは stderr に出して続け、終了コードを 1 にする
+ * ARCHIVE が "-" なら stdout に書き出し、メッセージは stderr に出す
+ */
+pub struct Tarball {
+    root: PathBuf,       // 入力フォルダ
+    filters: Vec<Regex>, // 除外するパス (--exclude)
+    entries: usize,      // 書き出したエントリー数
+    bytes: u64,          // 書き出したファイルのバイト数
+    errors: usize,       // 読めなかったエントリー数
+}
+
+impl Tarball {
+    pub fn new(root: &Path, filters: Vec<Regex>) -> Tarball {
+        Tarball {
+            root: root.to_path_buf(),
+            filters,
+            entries: 0,
+            bytes: 0,
+            errors: 0,
+        }
+    }
+
+    // tar を書き出す (書き込みのエラーは中断する)
+    fn write<W: Write>(&mut self, writer: W) -> Result<()> {
+        let mut builder = tar::Builder::new(writer);
+        builder.follow_symlinks(false);
+        let root: PathBuf = self.root.clone();
+        self.append_dir(&mut builder, &root)?;
+        builder.into_inner()?.flush()
+    }
+
+    // フォルダの中身を名前順に書き出す
+    fn append_dir<W: Write>(&mut self, builder: &mut tar::Builder<W>, dir: &Path) -> Result<()> {
+        let listed: Result<Vec<PathBuf>> =
+            fs::read_dir(dir).and_then(|x| x.map(|entry| entry.map(|e| e.path())).collect());
+        let mut paths: Vec<PathBuf> = match listed {
+            Ok(x) => x,
+            Err(e) => {
+                self.error(tr("search"), dir, e);
+                return Ok(());
+            }
+        };
+        paths.sort();
+        for path in paths {
+            if self
+                .filters
+                .iter()
+                .any(|x| x.is_match(&iomod::path_to_unix(&path)))
+            {
+                log::debug!("exclude: {:?}", path); // --exclude
+                continue;
+            }
+            let meta: fs::Metadata = match fs::symlink_metadata(&path) {
+                Ok(x) => x,
+                Err(e) => {
+                    self.error(tr("failed"), &path, e);
+                    continue;
+                }
+            };
+            let relative: &Path = path.strip_prefix(&self.root).unwrap_or(&path);
+            let name: String = iomod::path_to_unix(relative);
+            if meta.is_dir() {
+                builder.append_dir(&name, &path)?; // 空のフォルダも書き出す
+            } else if meta.is_file() {
+                let mut file: fs::File = match fs::File::open(&path) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        self.error(tr("failed"), &path, e);
+                        continue;
+                    }
+                };
+                builder.append_file(&name, &mut file)?;
+                self.bytes += meta.len();
+            } else {
+                builder.append_path_with_name(&path, &name)?; // リンク先をたどらない
+            }
+            self.entries += 1;
+            log::debug!("tar: {}", name);
+            if meta.is_dir() {
+                self.append_dir(builder, &path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn error(&mut self, label: &str, path: &Path, e: Error) {
+        let message = format!("{}: {:?} {}", iomod::red(label), path, e);
+        eprintln!("{}", message);
+        log::error!("{}", message);
+        self.errors += 1;
+    }
+}
+
+/**
+ * main - tar の書き出し
+ */
+pub fn main(args: &Tar) {
+    let input: &Path = Path::new(&args.input);
+    if !input.is_dir() {
+        let message = trf("not_folder", &[&format!("{:?}", args.input)]);
+        cli::fail(ErrorKind::ValueValidation, message);
+    }
+    let stdout: bool = args.archive == "-";
+    let mut tarball = Tarball::new(input, args.exclude.clone());
+    let result: Result<()> = if stdout {
+        tarball.write(io::stdout().lock())
+    } else {
+        fs::File::create(&args.archive).and_then(|x| tarball.write(BufWriter::new(x)))
+    };
+    let (message, code) = match result {
+        Ok(()) => {
+            let mb: String = format!("{:.1}", tarball.bytes as f64 / thmod::MB);
+            // 読めないエントリーがあればアーカイブは不完全
+            let code: i32 = if tarball.errors == 0 { 0 } else { 1 };
+            (iomod::cyan(trf("tar_done", &[&tarball.entries, &mb])), code)
+        }
+        Err(e) => {
+            let failed: String = iomod::red(tr("failed"));
+            (format!("{}: {:?} {}", failed, args.archive, e), 1)
+        }
+    };
+    if stdout {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+    if code != 0 {
+        std::process::exit(code);
+    }
+}
+
+/**
+ * Extractor - tar, zip ファイルを入力にする (copy <ARCHIVE> <DST>)
+ *
+ * 開始時にエントリーの一覧を読み (tar はデータの位置, zip はエントリー番号)、
+ * files::search_archive がファイルごとの DD を作って worker に渡す
+ * worker はそれぞれアーカイブを開いて並列に取り出す
+ * 出力ファイルの更新日時はエントリーの日時にするので、次回は judgment でスキップできる
+ * 帯域制限はエントリーを取り出した後にその長さで適用する
+ * 取り出せないエントリー (ハードリンク, 外を指す名前など) は走査の開始時に失敗として数える
+ */
+pub const _FILE: char = 'f';
+pub const _DIR: char = 'd';
+pub const _SYMLINK: char = 'l';
+
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: String,      // 出力フォルダからの相対パス (unix 形式)
+    pub kind: char,        // _FILE, _DIR, _SYMLINK
+    position: u64,         // tar: データの位置, zip: エントリー番号
+    pub size: u64,         // データの長さ
+    pub mtime: SystemTime, // 更新日時
+    mode: Option<u32>,     // パーミッション (unix)
+    link: Option<PathBuf>, // シンボリックリンクの対象
+}
+
+pub struct Extractor {
+    path: PathBuf,                                   // アーカイブファイル
+    zip: bool,                                       // zip (false: tar)
+    members: Vec<Member>,                            // エントリー (アーカイブの順)
+    skipped: Vec<(String, Error)>,                   // 取り出せないエントリーと理由
+    inputs: HashMap<String, usize>,                  // DD.input -> members の番号
+    archives: Arc<Mutex<Vec<ZipArchive<fs::File>>>>, // 解析済みの zip (worker が使い回す)
+}
+
+impl Extractor {
+    // エントリーの一覧を読む (拡張子 .zip は zip, それ以外は tar)
+    pub fn open(path: &Path) -> Result<Extractor> {
+        let extension: String = path
+            .extension()
+            .map(|x| x.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let zip: bool = extension == "zip";
+        // 日時の無いエントリーはアーカイブの日時にする (次回もスキップできるように)
+        let mtime: SystemTime = fs::metadata(path)?.modified()?;
+        let mut archives: Vec<ZipArchive<fs::File>> = Vec::new();
+        let mut skipped: Vec<(String, Error)> = Vec::new();
+        let list: Vec<Member> = if zip {
+            let mut archive = ZipArchive::new(fs::File::open(path)?)?;
+            let list: Vec<Member> = zip_members(&mut archive, mtime, &mut skipped)?;
+            archives.push(archive);
+            list
+        } else {
+            tar_members(path, mtime, &mut skipped)?
+        };
+        let mut extractor = Extractor {
+            path: path.to_path_buf(),
+            zip,
+            members: Vec::new(),
+            skipped: Vec::new(),
+            inputs: HashMap::new(),
+            archives: Arc::new(Mutex::new(archives)),
+        };
+        for (name, e) in skipped {
+            let input: String = iomod::path_to_string(path.join(name));
+            extractor.skipped.push((input, e));
+        }
+        for member in list {
+            let input: String = extractor.input(&member);
+            match extractor.inputs.get(&input) {
+                Some(n) => extractor.members[*n] = member, // 同じ名前は後のエントリー
+                None => {
+                    extractor.inputs.insert(input, extractor.members.len());
+                    extractor.members.push(member);
+                }
+            }
+        }
+        Ok(extractor)
+    }
+
+    // 表示用 (形式とエントリー数)
+    pub fn name(&self) -> String {
+        let format: &str = if self.zip { "zip" } else { "tar" };
+        format!("{}, {}", format, self.members.len())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn members(&self) -> &[Member] {
+        &self.members
+    }
+
+    // 取り出せないエントリー -> (アーカイブ/名前, 理由)
+    pub fn skipped(&self) -> &[(String, Error)] {
+        &self.skipped
+    }
+
+    // エントリーの DD.input (アーカイブ/名前)
+    pub fn input(&self, member: &Member) -> String {
+        iomod::path_to_string(self.path.join(&member.name))
+    }
+
+    // シンボリックリンクを作る (既存のファイルはそのまま)
+    pub fn symlink(&self, member: &Member, output: &Path) -> Result<()> {
+        if output.symlink_metadata().is_ok() {
+            return Ok(());
+        }
+        let Some(target) = &member.link else {
+            return Ok(());
+        };
+        #[cfg(unix)]
+        return std::os::unix::fs::symlink(target, output);
+        #[cfg(not(unix))]
+        {
+            log::warn!("symlink: {:?} -> {:?}", output, target);
+            Err(Error::new(
+                io::ErrorKind::Unsupported,
+                tr("archive_no_symlink"),
+            ))
+        }
+    }
+
+    // DD.input のエントリーを output に取り出す -> length
+    pub async fn extract(&self, input: &str, output: &str) -> Result<u64> {
+        let Some(n) = self.inputs.get(input) else {
+            return Err(Error::new(io::ErrorKind::NotFound, input.to_string()));
+        };
+        let member: Member = self.members[*n].clone();
+        let output: PathBuf = PathBuf::from(output);
+        if !self.zip {
+            let path: PathBuf = self.path.clone();
+            return async_std::task::spawn_blocking(move || extract_tar(&path, &member, &output))
+                .await;
+        }
+        // zip の一覧の解析は1回だけ (空いていなければ開き直す, 最大で worker の数)
+        let (path, archives) = (self.path.clone(), self.archives.clone());
+        async_std::task::spawn_blocking(move || {
+            let reused: Option<ZipArchive<fs::File>> = archives.lock().unwrap().pop();
+            let mut archive = match reused {
+                Some(x) => x,
+                None => ZipArchive::new(fs::File::open(&path)?)?,
+            };
+            let result: Result<u64> = extract_zip(&mut archive, &member, &output);
+            archives.lock().unwrap().push(archive);
+            result
+        })
+        .await
+    }
+}
+
+// tar のエントリー (データの位置を記録する)
+fn tar_members(
+    path: &Path,
+    mtime: SystemTime,
+    skipped: &mut Vec<(String, Error)>,
+) -> Result<Vec<Member>> {
+    let mut archive = tar::Archive::new(fs::File::open(path)?);
+    let mut members: Vec<Member> = Vec::new();
+    for entry in archive.entries_with_seek()? {
+        let entry = entry?;
+        let raw: String = entry.path()?.to_string_lossy().to_string();
+        if entry.path()?.components().all(|x| x == Component::CurDir) {
+            continue; // アーカイブのルート ("./")
+        }
+        let Some(name) = safe_name(&entry.path()?) else {
+            skipped.push((
+                raw,
+                Error::new(io::ErrorKind::InvalidData, tr("archive_unsafe")),
+            ));
+            continue;
+        };
+        let header = entry.header();
+        let kind: char = match header.entry_type() {
+            tar::EntryType::Regular | tar::EntryType::Continuous => _FILE,
+            tar::EntryType::Directory => _DIR,
+            tar::EntryType::Symlink => _SYMLINK,
+            x => {
+                // ハードリンク, デバイスなど
+                let message: String = trf("archive_unsupported", &[&format!("{:?}", x)]);
+                skipped.push((raw, Error::new(io::ErrorKind::Unsupported, message)));
+                continue;
+            }
+        };
+        members.push(Member {
+            name,
+            kind,
+            position: entry.raw_file_position(),
+            size: entry.size(),
+            mtime: match header.mtime() {
+                Ok(x) if x > 0 => UNIX_EPOCH + Duration::from_secs(x),
+                _ => mtime,
+            },
+            mode: header.mode().ok(),
+            link: entry.link_name()?.map(|x| x.to_path_buf()),
+        });
+    }
+    Ok(members)
+}
+
+// zip のエントリー (エントリー番号を記録する)
+fn zip_members(
+    archive: &mut ZipArchive<fs::File>,
+    mtime: SystemTime,
+    skipped: &mut Vec<(String, Error)>,
+) -> Result<Vec<Member>> {
+    let mut members: Vec<Member> = Vec::new();
+    for index in 0..archive.len() {
+        let file = archive.by_index_raw(index)?;
+        let Some(name) = file.enclosed_name().and_then(|x| safe_name(&x)) else {
+            let raw: String = file.name().to_string();
+            skipped.push((
+                raw,
+                Error::new(io::ErrorKind::InvalidData, tr("archive_unsafe")),
+            ));
+            continue;
+        };
+        let kind: char = if file.is_dir() {
+            _DIR
+        } else if file.is_symlink() {
+            _SYMLINK
+        } else {
+            _FILE
+        };
+        let mut member = Member {
+            name,
+            kind,
+            position: index as u64,
+            size: file.size(),
+            mtime: file.last_modified().and_then(zip_time).unwrap_or(mtime),
+            mode: file.unix_mode(),
+            link: None,
+        };
+        drop(file);
+        if kind == _SYMLINK {
+            // リンク先はエントリーのデータ (圧縮されていることもある)
+            let mut target: String = String::new();
+            archive.by_index(index)?.read_to_string(&mut target)?;
+            member.link = Some(PathBuf::from(target));
+        }
+        members.push(member);
+    }
+    Ok(members)
+}
+
+// zip の日時 (ローカル時刻) -> SystemTime
+fn zip_time(time: zip::DateTime) -> Option<SystemTime> {
+    let local = NaiveDate::from_ymd_opt(time.year() as i32, time.month() as u32, time.day() as u32)
+        .and_then(|x| {
+            x.and_hms_opt(
+                time.hour() as u32,
+                time.minute() as u32,
+                time.second() as u32,
+            )
+        })
+        .and_then(|x| Local.from_local_datetime(&x).earliest());
+    local.map(|x| x.into())
+}
+
+// 出力フォルダの外に出ない名前だけを使う ("..", 絶対パスは除く)
+fn safe_name(path: &Path) -> Option<String> {
+    let mut parts: Vec<String> = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(x) => parts.push(x.to_string_lossy().to_string()),
+            Component::CurDir => (),
+            _ => return None,
+        }
+    }
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+// tar のエントリーを取り出す (データの位置から読む) -> length
+fn extract_tar(path: &Path, member: &Member, output: &Path) -> Result<u64> {
+    let mut writer = fs::File::create(output)?;
+    let mut file = fs::File::open(path)?;
+    file.seek(SeekFrom::Start(member.position))?;
+    let length: u64 = io::copy(&mut file.take(member.size), &mut writer)?;
+    finish(writer, member, length)
+}
+
+// zip のエントリーを取り出す (CRC を検査する) -> length
+fn extract_zip(archive: &mut ZipArchive<fs::File>, member: &Member, output: &Path) -> Result<u64> {
+    let mut writer = fs::File::create(output)?;
+    let mut file = archive.by_index(member.position as usize)?;
+    let length: u64 = io::copy(&mut file, &mut writer)?;
+    finish(writer, member, length)
+}
+
+// 長さを確かめ、更新日時とパーミッションを設定する -> length
+fn finish(writer: fs::File, member: &Member, length: u64) -> Result<u64> {
+    if length != member.size {
+        return Err(Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "archive: truncated",
+        ));
+    }
+    writer.set_modified(member.mtime)?;
+    #[cfg(unix)]
+    if let Some(mode) = member.mode {
+        use std::os::unix::fs::PermissionsExt;
+        writer.set_permissions(fs::Permissions::from_mode(mode & 0o7777))?;
+    }
+    Ok(length)
+}
+
+// root/name の途中 (name 自身を含む) にある既存のシンボリックリンク
+pub fn through_symlink(root: &Path, name: &str) -> Option<PathBuf> {
+    let mut path: PathBuf = root.to_path_buf();
+    for part in name.split('/').filter(|x| !x.is_empty()) {
+        path.push(part);
+        if path
+            .symlink_metadata()
+            .is_ok_and(|x| x.file_type().is_symlink())
+        {
+            return Some(path);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+#[test]
+fn tarball_test() {
+    let root = iomod::TempDir::new("tarball_test");
+    fs::create_dir_all(root.join("in/b/empty")).unwrap();
+    fs::write(root.join("in/b/2.txt"), "two").unwrap();
+    fs::write(root.join("in/a.txt"), "one").unwrap();
+    fs::create_dir_all(root.join("in/skip")).unwrap();
+    fs::write(root.join("in/skip/3.txt"), "three").unwrap();
+    #[cfg(unix)]
+    std::os::unix::fs::symlink("a.txt", root.join("in/link")).unwrap();
+    // 名前順に書き出し、除外したフォルダは中身ごと書き出さない
+    let filters: Vec<Regex> = vec![Regex::new("/skip$").unwrap()];
+    let mut tarball = Tarball::new(&root.join("in"), filters);
+    let mut data: Vec<u8> = Vec::new();
+    tarball.write(&mut data).unwrap();
+    assert_eq!((6, 0), (tarball.bytes, tarball.errors));
+    let mut archive = tar::Archive::new(data.as_slice());
+    let names: Vec<String> = archive
+        .entries()
+        .unwrap()
+        .map(|x| iomod::path_to_unix(x.unwrap().path().unwrap()))
+        .collect();
+    let mut expected: Vec<&str> = vec!["a.txt", "b", "b/2.txt", "b/empty"];
+    #[cfg(unix)]
+    expected.push("link");
+    assert_eq!(expected.len(), tarball.entries);
+    assert_eq!(expected, names);
+}
+
+#[cfg(test)]
+#[test]
+fn extractor_test() {
+    let root = iomod::TempDir::new("extractor_test");
+    fs::create_dir_all(root.join("out")).unwrap();
+    let mtime: SystemTime = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+    // tar: 同じ名前は後のエントリー, ハードリンクは失敗として数える
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Link);
+    header.set_size(0);
+    header.set_cksum();
+    builder.append_link(&mut header, "hard", "b.txt").unwrap();
+    for (name, data) in [("a/x.txt", "old"), ("a/x.txt", "new!"), ("b.txt", "b")] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o640);
+        header.set_mtime(1_700_000_000);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, name, data.as_bytes())
+            .unwrap();
+    }
+    fs::write(root.join("in.tar"), builder.into_inner().unwrap()).unwrap();
+    let tar = Extractor::open(&root.join("in.tar")).unwrap();
+    assert_eq!("tar, 2", tar.name());
+    let skipped: Vec<String> = tar.skipped().iter().map(|x| x.0.clone()).collect();
+    assert_eq!(
+        vec![iomod::path_to_string(root.join("in.tar/hard"))],
+        skipped
+    );
+    let member: &Member = &tar.members()[0];
+    assert_eq!(("a/x.txt", 4), (member.name.as_str(), member.size));
+    let output: PathBuf = root.join("out/x.txt");
+    let input: String = tar.input(member);
+    let length = async_std::task::block_on(tar.extract(&input, &iomod::path_to_string(&output)));
+    assert_eq!(4, length.unwrap());
+    assert_eq!("new!", fs::read_to_string(&output).unwrap());
+    assert_eq!(mtime, fs::metadata(&output).unwrap().modified().unwrap());
+
+    // zip
+    let mut writer = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+    let options = zip::write::SimpleFileOptions::default();
+    writer.add_directory("d/", options).unwrap();
+    writer.start_file("d/z.txt", options).unwrap();
+    writer.write_all(b"zipped").unwrap();
+    writer.add_symlink("d/link", "z.txt", options).unwrap();
+    fs::write(root.join("in.zip"), writer.finish().unwrap().into_inner()).unwrap();
+    let zip = Extractor::open(&root.join("in.zip")).unwrap();
+    let kinds: Vec<(&str, char)> = zip
+        .members()
+        .iter()
+        .map(|x| (x.name.as_str(), x.kind))
+        .collect();
+    assert_eq!(
+        vec![("d", _DIR), ("d/z.txt", _FILE), ("d/link", _SYMLINK)],
+        kinds
+    );
+    assert_eq!(Some(PathBuf::from("z.txt")), zip.members()[2].link);
+    let output: PathBuf = root.join("out/z.txt");
+    let input: String = zip.input(&zip.members()[1]);
+    let length = async_std::task::block_on(zip.extract(&input, &iomod::path_to_string(&output)));
+    assert_eq!(6, length.unwrap());
+    assert_eq!("zipped", fs::read_to_string(&output).unwrap());
+
+    assert_eq!(None, safe_name(Path::new("../etc/passwd")));
+    assert_eq!(None, safe_name(Path::new("/etc/passwd")));
+    assert_eq!(Some("a/b".to_string()), safe_name(Path::new("./a/b")));
+
+    // 出力フォルダの中のシンボリックリンクを通るエントリーは書き込まない
+    #[cfg(unix)]
+    {
+        let out: PathBuf = root.join("out");
+        std::os::unix::fs::symlink(root.join("outside"), out.join("link")).unwrap();
+        assert_eq!(
+            Some(out.join("link")),
+            through_symlink(&out, "link/payload.txt")
+        );
+        assert_eq!(Some(out.join("link")), through_symlink(&out, "link"));
+        assert_eq!(None, through_symlink(&out, "x.txt"));
+    }
+}
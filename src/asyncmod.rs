@@ -15,19 +15,22 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 // https://docs.rs/async-std/latest/async_std/io/trait.WriteExt.html#method.write_all
+use crate::thmod::Scheduler;
 use crate::Path;
 use async_std::fs;
 use async_std::fs::File;
 use async_std::io::{ReadExt, WriteExt};
+use blake3::Hasher;
 use futures::prelude::*;
 use futures::StreamExt;
+use std::io::{Error, Result};
 
 // #[derive(Debug, Clone)] // I/O buffer
-struct IOBUF {
+struct IoBuf {
     buf: Vec<u8>, // this field does not implement `Copy`
     length: usize,
 }
-impl IOBUF {
+impl IoBuf {
     // fn to_slice(&self) -> &[u8] {
     //     &self.buf[..self.length]
     // }
@@ -42,64 +45,129 @@ impl IOBUF {
 pub async fn copy<P: AsRef<Path> + std::convert::AsRef<async_std::path::Path>>(
     from: P,
     to: P,
-) -> u64 {
-    let length = fs::copy(from, to).await;
-    length.unwrap()
+) -> Result<u64> {
+    fs::copy(from, to).await
+}
+
+/**
+ * copy progress from to -> length
+ *
+ * fs::copy でコピーし、コピー中は出力ファイルの長さを POLL ミリ秒ごとに調べて進捗に加算する
+ * 残りは完了したときに加算する (帯域制限中は使わない)
+ */
+pub async fn copy_progress<
+    P: AsRef<Path> + std::convert::AsRef<async_std::path::Path> + std::marker::Copy,
+>(
+    from: P,
+    to: P,
+    sched: &Scheduler,
+    slot: usize,
+) -> Result<u64> {
+    use async_std::task;
+    const POLL: u64 = 200;
+    let mut done: u64 = 0; // 加算済みのバイト数
+    let copy = copy(from, to).fuse();
+    futures::pin_mut!(copy);
+    loop {
+        let mut poll = task::sleep(std::time::Duration::from_millis(POLL))
+            .boxed()
+            .fuse();
+        futures::select! {
+            result = copy => {
+                let length: u64 = result?;
+                sched.transferred(slot, length.saturating_sub(done) as usize).await;
+                return Ok(length);
+            }
+            _ = poll => {
+                let length: u64 = get_meta_len(&to).await.unwrap_or(0);
+                if length > done {
+                    sched.transferred(slot, (length - done) as usize).await;
+                    done = length;
+                }
+            }
+        }
+    }
 }
 
 /**
  * copy maxbuf from to -> length
+ *
+ * チャンクごとにコピー済みのバイト数を加算する (帯域制限もここで行う)
+ * hasher があればコピーしながらハッシュを計算する (--manifest)
  */
 pub async fn copymax<
     P: AsRef<Path> + std::convert::AsRef<async_std::path::Path> + std::marker::Copy,
 >(
     from: P,
     to: P,
-) -> u64 {
+    sched: &Scheduler,
+    slot: usize,
+    mut hasher: Option<&mut Hasher>,
+) -> Result<u64> {
     const BUFSIZE: usize = 1024 * 1024;
-    let mut fr = File::open(from).await.unwrap();
-    let mut fw = File::create(to).await.unwrap();
+    let mut fr = File::open(from).await?;
+    let mut fw = File::create(to).await?;
     let mut result: usize = 0;
-    let mut io = IOBUF {
+    let mut io = IoBuf {
         buf: vec![0_u8; BUFSIZE],
         length: 0,
     };
     loop {
-        io.length = ReadExt::read(&mut fr, &mut io.buf).await.unwrap();
+        io.length = ReadExt::read(&mut fr, &mut io.buf).await?;
         if io.length == 0 {
             break;
         }
-        let _ = WriteExt::write_all(&mut fw, &io.buf[..io.length]).await;
+        sched.transferred(slot, io.length).await; // 進捗, 帯域制限
+        WriteExt::write_all(&mut fw, &io.buf[..io.length]).await?;
+        if let Some(h) = hasher.as_mut() {
+            h.update(&io.buf[..io.length]);
+        }
         result += io.length;
     }
-    let _ = WriteExt::flush(&mut fw);
-    let fromsize: usize = get_meta_len(&from).await.try_into().unwrap();
-    assert_eq!(fromsize, result, "(original:result) ");
-    result as u64
+    WriteExt::flush(&mut fw).await?;
+    let fromsize: usize = get_meta_len(&from).await? as usize;
+    check_length(fromsize, result)
+}
+
+/**
+ * copy attributes - 更新日時とパーミッションを写す (mv と同じ)
+ */
+pub fn copy_attributes<P: AsRef<Path>>(from: P, to: P) -> Result<()> {
+    let metadata: std::fs::Metadata = std::fs::metadata(from.as_ref())?;
+    let file = std::fs::File::options().write(true).open(to.as_ref())?;
+    file.set_modified(metadata.modified()?)?;
+    std::fs::set_permissions(to.as_ref(), metadata.permissions())
 }
 
 /**
  * copy channel from | to -> length
+ *
+ * 受信したチャンクごとにコピー済みのバイト数を加算する (帯域制限もここで行う)
+ * hasher があればコピーしながらハッシュを計算する (--manifest)
  */
 pub async fn copych<P: AsRef<Path> + std::convert::AsRef<async_std::path::Path>>(
     from: P,
     to: P,
-) -> u64 {
+    sched: &Scheduler,
+    slot: usize,
+    mut hasher: Option<&mut Hasher>,
+) -> Result<u64> {
     use async_std::task;
     use async_std::task::JoinHandle;
     use futures::channel::mpsc;
     const BUFSIZE: usize = 1024 * 1024;
-    let fromsize: usize = get_meta_len(&from).await.try_into().unwrap();
-    let mut fr = File::open(from).await.unwrap();
-    let mut fw = File::create(to).await.unwrap();
+    let fromsize: usize = get_meta_len(&from).await? as usize;
+    let mut fr = File::open(from).await?;
+    let mut fw = File::create(to).await?;
     let (mut tx, mut rx) = mpsc::channel(4);
     let _handle: JoinHandle<()> = task::spawn(async move {
         loop {
-            let mut io = IOBUF {
+            let mut io = IoBuf {
                 buf: vec![0_u8; BUFSIZE],
                 length: 0,
             };
-            io.length = ReadExt::read(&mut fr, &mut io.buf).await.unwrap();
+            // 読み込みエラーは送信を打ち切る (長さの比較でエラーになる)
+            io.length = ReadExt::read(&mut fr, &mut io.buf).await.unwrap_or(0);
             if io.length == 0 {
                 break;
             }
@@ -110,11 +178,45 @@ pub async fn copych<P: AsRef<Path> + std::convert::AsRef<async_std::path::Path>>
     let mut result: usize = 0; // 受信
     while let Some(received) = rx.next().await {
         result += received.length; // write の前に使用する
-        let _ = WriteExt::write_all(&mut fw, &received.buf[..received.length]).await;
+        sched.transferred(slot, received.length).await; // 進捗, 帯域制限
+        WriteExt::write_all(&mut fw, &received.buf[..received.length]).await?;
+        if let Some(h) = hasher.as_mut() {
+            h.update(&received.buf[..received.length]);
+        }
+    }
+    WriteExt::flush(&mut fw).await?;
+    check_length(fromsize, result)
+}
+
+// 入力ファイルの長さとコピーした長さを比較する (original:result)
+fn check_length(fromsize: usize, result: usize) -> Result<u64> {
+    if fromsize != result {
+        let message = format!("length mismatch (original:result) {}:{}", fromsize, result);
+        return Err(Error::other(message));
+    }
+    Ok(result as u64)
+}
+
+/**
+ * hash file - コピーしないファイル (rename, skip) のハッシュを計算する
+ */
+pub async fn hash_file<P: AsRef<Path> + std::convert::AsRef<async_std::path::Path>>(
+    path: P,
+    hasher: &mut Hasher,
+) -> Result<u64> {
+    const BUFSIZE: usize = 1024 * 1024;
+    let mut fr = File::open(path).await?;
+    let mut buf: Vec<u8> = vec![0_u8; BUFSIZE];
+    let mut result: u64 = 0;
+    loop {
+        let length: usize = ReadExt::read(&mut fr, &mut buf).await?;
+        if length == 0 {
+            break;
+        }
+        hasher.update(&buf[..length]);
+        result += length as u64;
     }
-    let _ = WriteExt::flush(&mut fw);
-    assert_eq!(fromsize, result, "(original:result) ");
-    result as u64
+    Ok(result)
 }
 
 /**
@@ -125,9 +227,8 @@ pub async fn copych<P: AsRef<Path> + std::convert::AsRef<async_std::path::Path>>
 pub async fn rename_file<P: AsRef<Path> + std::convert::AsRef<async_std::path::Path>>(
     from: P,
     to: P,
-) -> () {
-    let rs = fs::rename(from, to).await;
-    rs.unwrap();
+) -> Result<()> {
+    fs::rename(from, to).await
 }
 
 /**
@@ -135,12 +236,12 @@ pub async fn rename_file<P: AsRef<Path> + std::convert::AsRef<async_std::path::P
  *
  * async fn remove_file(path: impl AsRef<Path>) -> Result<()>
  */
-pub async fn remove_file<P: AsRef<Path>>(path: P) -> () {
+pub async fn remove_file<P: AsRef<Path>>(path: P) -> Result<()> {
     let p: &Path = path.as_ref();
     if p.is_file() {
-        let _ = fs::remove_file(p).await;
+        fs::remove_file(p).await?;
     }
-    ()
+    Ok(())
 }
 
 // https://runebook.dev/ja/docs/rust/std/fs/struct.metadata
@@ -151,10 +252,9 @@ pub async fn remove_file<P: AsRef<Path>>(path: P) -> () {
  */
 pub async fn get_meta_len<P: AsRef<Path> + std::convert::AsRef<async_std::path::Path>>(
     path: P,
-) -> u64 {
-    let rs = fs::metadata(path).await;
-    let metadata: u64 = rs.unwrap().len();
-    metadata
+) -> Result<u64> {
+    let metadata = fs::metadata(path).await?;
+    Ok(metadata.len())
 }
 
 /* write n
@@ -16,15 +16,14 @@
  */
 // https://doc.rust-lang.org/std/sync/atomic/
 // https://runebook.dev/ja/docs/rust/std/sync/atomic/struct.atomici32
-use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
 
 /**
  * AtomicInt の実装
  */
 // 演算結果を返す (オーバーロードは無いようだ！)
 pub fn atomic_get(a: &AtomicI32) -> i32 {
-    let x = (*a).load(Ordering::SeqCst);
-    x
+    (*a).load(Ordering::SeqCst)
 }
 pub fn atomic_set(a: &AtomicI32, n: i32) -> i32 {
     (*a).store(n, Ordering::SeqCst);
@@ -34,6 +33,14 @@ pub fn atomic_add(a: &AtomicI32, n: i32) -> i32 {
     let x = (*a).fetch_add(n, Ordering::SeqCst); // 加算前の値が帰る
     x + n
 }
+// バイト数用 (i32 では 2GB を超えられない)
+pub fn atomic_get64(a: &AtomicU64) -> u64 {
+    (*a).load(Ordering::SeqCst)
+}
+pub fn atomic_add64(a: &AtomicU64, n: u64) -> u64 {
+    let x = (*a).fetch_add(n, Ordering::SeqCst); // 加算前の値が帰る
+    x + n
+}
 /**
  * AtomicBool での実装をあきらめた ^^);
  */
@@ -64,11 +71,11 @@ fn test() {
     assert_eq!(2, atomic_add(&_COUNTER, 1));
     assert_eq!(2, atomic_get(&_COUNTER));
 
-    assert_eq!(true, atomic_bool_set(&_BOOL, true));
-    assert_eq!(true, atomic_bool_get(&_BOOL));
+    assert!(atomic_bool_set(&_BOOL, true));
+    assert!(atomic_bool_get(&_BOOL));
 }
 
 pub fn _run() {
     atomic_set(&_COUNTER, 0);
-    assert_eq!(false, atomic_bool_get_set(&_BOOL, true));
+    assert!(!atomic_bool_get_set(&_BOOL, true));
 }
@@ -0,0 +1,92 @@
+/**
+ * Copyright (C) 2023 awk4j - https://ja.osdn.net/projects/awk4j/
+ * <p>
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ * <p>
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ * <p>
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::error::ErrorKind;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::cli;
+use crate::cli::{Algorithm, Bench};
+use crate::daemon;
+use crate::features::Features;
+use crate::files;
+use crate::files::EE;
+use crate::iomod;
+use crate::messages::{tr, trf};
+use crate::thmod;
+use crate::thmod::Scheduler;
+
+/**
+ * Bench - コピーアルゴリズムのスループットを比較する
+ *
+ * アルゴリズムごとに DST/bench-<algorithm> へ SRC をコピーして時間を計り、
+ * 終わったら削除する (出力が空なので全ファイルをコピーする)
+ */
+pub fn main(bench: &Bench) {
+    let input: &Path = Path::new(&bench.input);
+    if !input.is_dir() {
+        let message = trf("not_folder", &[&format!("{:?}", bench.input)]);
+        cli::fail(ErrorKind::ValueValidation, message);
+    }
+    println!(
+        " {}: {}",
+        iomod::blue(tr("input_folder")),
+        iomod::path_to_unix(input)
+    );
+    for algorithm in [Algorithm::Std, Algorithm::Maxbuf, Algorithm::Channel] {
+        let name: String = cli::value_name(&algorithm);
+        let output: PathBuf = Path::new(&bench.output).join(format!("bench-{}", name));
+        let _ = fs::remove_dir_all(&output);
+        iomod::mkdir(&output);
+        let mut sched = Scheduler::new(thmod::_FIFO, 2048, bench.threads);
+        sched.set_quiet(true);
+        let sched = Arc::new(sched);
+        let ee = EE {
+            cmr_mode: files::_COPY,
+            algorithm: algorithm.code(),
+        };
+        let start: Instant = Instant::now();
+        let output: String = iomod::path_to_string(&output);
+        daemon::main(
+            &sched,
+            &Arc::new(Features::default()),
+            &bench.input,
+            &output,
+            ee,
+        );
+        let secs: f64 = start.elapsed().as_secs_f64();
+        let _ = fs::remove_dir_all(&output);
+        let (files, failed, bytes) = sched.totals();
+        let mbps: f64 = bytes as f64 / thmod::MB / secs.max(f64::EPSILON);
+        let status: String = trf(
+            "bench",
+            &[
+                &files,
+                &format!("{:.1}", bytes as f64 / thmod::MB),
+                &format!("{:.3}", secs),
+                &format!("{:.1}", mbps),
+            ],
+        );
+        if failed > 0 {
+            let failed: String = format!("{} {}", failed, tr("failed"));
+            println!("{:>8}: {} {}", name, status, iomod::red(failed));
+        } else {
+            println!("{:>8}: {}", name, iomod::cyan(status));
+        }
+    }
+}
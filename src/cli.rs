@@ -0,0 +1,489 @@
+/**
+ * Copyright (C) 2023 awk4j - https://ja.osdn.net/projects/awk4j/
+ * <p>
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ * <p>
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ * <p>
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use regex::Regex;
+
+use crate::config;
+use crate::dedupe;
+use crate::files;
+use crate::thmod;
+use crate::versions;
+
+/*
+ * Cli - コマンドラインの定義 (clap)
+ *
+ * copy-asyncstd <copy|move|rename> <SRC> <DST> [OPTIONS]
+ * copy-asyncstd verify <MANIFEST> [DIR]
+ * copy-asyncstd purge [DIR] --older-than <DAYS>
+ * copy-asyncstd tar <SRC> <ARCHIVE|-> [--exclude <REGEX>]
+ * copy-asyncstd dupes <DIR> [--link]
+ * copy-asyncstd bench <SRC> <DST>
+ * copy-asyncstd run <PROFILE> [OPTIONS]
+ * copy-asyncstd completions <SHELL>
+ *
+ * 不正な入力はエラーメッセージを表示して終了する (終了コード 2)
+ */
+#[derive(Debug, Parser)]
+#[command(name = "copy-asyncstd", version, args_override_self = true)]
+#[command(about = "Copy, move or rename folder trees with async-std workers")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+    /// Message language [default: from LC_ALL, LC_MESSAGES or LANG]
+    #[arg(long, global = true, value_parser = ["en", "ja"])]
+    pub lang: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Copy new and changed files from SRC to DST
+    Copy(Job),
+    /// Copy files from SRC to DST, then delete the sources
+    Move(Job),
+    /// Move files from SRC to DST with rename (copy, verify and delete across drives)
+    Rename(Job),
+    /// Check a folder against a manifest written with --manifest
+    Verify {
+        /// Manifest file (JSON Lines)
+        manifest: String,
+        /// Folder to check [default: the folder of the manifest]
+        dir: Option<String>,
+    },
+    /// Delete quarantined files older than DAYS (see --trash)
+    Purge {
+        /// Quarantine folder [default: files this tool moved to the freedesktop trash]
+        dir: Option<String>,
+        /// Age in days
+        #[arg(long, value_name = "DAYS")]
+        older_than: u64,
+    },
+    /// Write SRC as a tar archive to a file or stdout
+    Tar(Tar),
+    /// Find files with identical content, optionally replacing them with hard links
+    Dupes(Dupes),
+    /// Compare the throughput of the copy algorithms
+    Bench(Bench),
+    /// Run a named profile from the config file
+    Run {
+        /// Profile name ([profiles.<name>] in the config file)
+        profile: String,
+        /// Config file [default: ./copy-asyncstd.toml, ~/.config/copy-asyncstd/config.toml]
+        #[arg(long, value_name = "FILE")]
+        config: Option<String>,
+        /// Options that override the profile
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        options: Vec<String>,
+    },
+    /// Print a shell completion script
+    Completions {
+        /// Shell
+        shell: clap_complete::Shell,
+    },
+}
+
+// copy, move, rename のオプション
+#[derive(Debug, Args)]
+pub struct Job {
+    /// Input folder, or a tar/zip archive (copy only)
+    #[arg(value_name = "SRC")]
+    pub input: String,
+    /// Output folder (created if missing)
+    #[arg(value_name = "DST")]
+    pub output: String,
+    /// Copy algorithm
+    #[arg(short, long, value_enum, default_value_t = Algorithm::Std)]
+    pub algorithm: Algorithm,
+    /// Worker threads for small files, and for large files after '/'
+    #[arg(short = 'j', long, value_name = "N[/M]", default_value = "3", value_parser = parse_threads)]
+    pub threads: [i32; 2],
+    /// Scheduling policy of the queue
+    #[arg(short, long, value_enum, default_value_t = Queue::Fifo)]
+    pub queue: Queue,
+    /// Maximum number of queued requests
+    #[arg(long, value_name = "N", default_value_t = 2048, value_parser = parse_capacity)]
+    pub capacity: usize,
+    /// Concurrent transfers per device, for all devices (N) or one mount point (MOUNT=N)
+    #[arg(long = "device-limit", value_name = "[MOUNT=]N", value_parser = parse_device)]
+    pub devices: Vec<(String, i32)>,
+    /// Bandwidth limit in bytes/sec (K, M, G), optionally only between hours H-H
+    #[arg(long, value_name = "RATE[,H-H]", value_parser = parse_bandwidth)]
+    pub bwlimit: Option<Bandwidth>,
+    /// Skip files whose path matches the regular expression
+    #[arg(long, value_name = "REGEX", value_parser = parse_regex)]
+    pub exclude: Vec<Regex>,
+    /// Read each output back and compare it with the copied data
+    #[arg(long, overrides_with = "no_verify")]
+    pub verify: bool,
+    /// Do not verify (overrides --verify from a profile)
+    #[arg(long, overrides_with = "verify")]
+    pub no_verify: bool,
+    /// Quarantine deleted and overwritten files in DIR/<date>, or in the freedesktop trash without a value
+    #[arg(long, value_name = "DIR", num_args = 0..=1, require_equals = true, default_missing_value = "", value_parser = parse_trash)]
+    pub trash: Option<String>,
+    /// Write into a new dated folder under DST, hard-linking unchanged files from the previous one (copy only)
+    #[arg(long)]
+    pub snapshot: bool,
+    /// Link files whose content already exists in DST instead of copying them
+    #[arg(long, value_enum, value_name = "METHOD", num_args = 0..=1, require_equals = true, default_missing_value = "link")]
+    pub dedupe: Option<Sharing>,
+    /// Keep overwritten output files as versions, in DST/.versions (tree) or next to the file (suffix)
+    #[arg(long, value_enum, value_name = "STYLE", num_args = 0..=1, require_equals = true, default_missing_value = "tree")]
+    pub versions: Option<Version>,
+    /// Keep at most N versions of each file (0: all)
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub keep_versions: usize,
+    /// Delete versions older than DAYS (0: never)
+    #[arg(long, value_name = "DAYS", default_value_t = 0)]
+    pub keep_days: u64,
+    /// Write JSON Lines events to FILE, or to stdout without a value
+    #[arg(long, value_name = "FILE", num_args = 0..=1, require_equals = true, default_missing_value = "")]
+    pub json: Option<String>,
+    /// Write an HTML report of the run
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<String>,
+    /// Write a manifest of the output files with BLAKE3 hashes
+    #[arg(long, value_name = "FILE")]
+    pub manifest: Option<String>,
+    /// Write a rotating log file
+    #[arg(long, value_name = "FILE")]
+    pub log: Option<String>,
+    /// Log level
+    #[arg(long, value_name = "LEVEL", default_value = "info",
+        value_parser = ["off", "error", "warn", "info", "debug", "trace"])]
+    pub log_level: String,
+}
+
+// bench のオプション
+#[derive(Debug, Args)]
+pub struct Bench {
+    /// Input folder
+    #[arg(value_name = "SRC")]
+    pub input: String,
+    /// Work folder (bench-<algorithm> is created and removed for each run)
+    #[arg(value_name = "DST")]
+    pub output: String,
+    /// Worker threads for small files, and for large files after '/'
+    #[arg(short = 'j', long, value_name = "N[/M]", default_value = "3", value_parser = parse_threads)]
+    pub threads: [i32; 2],
+}
+
+// tar のオプション
+#[derive(Debug, Args)]
+pub struct Tar {
+    /// Input folder
+    #[arg(value_name = "SRC")]
+    pub input: String,
+    /// Archive file, or '-' for stdout
+    #[arg(value_name = "ARCHIVE")]
+    pub archive: String,
+    /// Skip files whose path matches the regular expression
+    #[arg(long, value_name = "REGEX", value_parser = parse_regex)]
+    pub exclude: Vec<Regex>,
+}
+
+// dupes のオプション
+#[derive(Debug, Args)]
+pub struct Dupes {
+    /// Folder to search
+    #[arg(value_name = "DIR")]
+    pub dir: String,
+    /// Replace duplicates with hard links to the first file of each group
+    #[arg(long)]
+    pub link: bool,
+    /// Hashing threads for small files, and for large files after '/'
+    #[arg(short = 'j', long, value_name = "N[/M]", default_value = "3", value_parser = parse_threads)]
+    pub threads: [i32; 2],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Algorithm {
+    Std,
+    Maxbuf,
+    Channel,
+    #[value(hide = true)]
+    Test,
+}
+
+impl Algorithm {
+    pub fn code(self) -> u8 {
+        match self {
+            Algorithm::Std => files::_STD,
+            Algorithm::Maxbuf => files::_MAXBUF,
+            Algorithm::Channel => files::_CHANNEL,
+            Algorithm::Test => files::_TEST,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Queue {
+    /// First in, first out
+    Fifo,
+    /// Last in, first out
+    Lifo,
+    /// Largest file first
+    Largest,
+    /// Smallest file first
+    Smallest,
+    /// Keep folders together
+    Dir,
+}
+
+impl Queue {
+    pub fn policy(self) -> char {
+        match self {
+            Queue::Fifo => thmod::_FIFO,
+            Queue::Lifo => thmod::_LIFO,
+            Queue::Largest => thmod::_LARGEST,
+            Queue::Smallest => thmod::_SMALLEST,
+            Queue::Dir => thmod::_DIRECTORY,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Version {
+    /// DST/.versions/<path>.~<time>~
+    Tree,
+    /// <path>.~<time>~ next to the file
+    Suffix,
+}
+
+impl Version {
+    pub fn style(self) -> char {
+        match self {
+            Version::Tree => versions::_TREE,
+            Version::Suffix => versions::_SUFFIX,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Sharing {
+    /// Hard link
+    Link,
+    /// Copy-on-write clone (Btrfs, XFS)
+    Reflink,
+}
+
+impl Sharing {
+    pub fn method(self) -> char {
+        match self {
+            Sharing::Link => dedupe::_LINK,
+            Sharing::Reflink => dedupe::_REFLINK,
+        }
+    }
+}
+
+// 帯域制限 (--bwlimit)
+#[derive(Debug, Clone)]
+pub struct Bandwidth {
+    pub text: String,              // 指定された文字列
+    pub rate: u64,                 // bytes/sec
+    pub hours: Option<(u32, u32)>, // 制限する時間帯
+}
+
+// 値の名前 (ヘッダー, イベント用)
+pub fn value_name<T: ValueEnum>(value: &T) -> String {
+    value
+        .to_possible_value()
+        .map(|x| x.get_name().to_string())
+        .unwrap_or_default()
+}
+
+/**
+ * parse - コマンドラインを解析する
+ *
+ * run <profile> はプロファイルを展開して、もう一度解析する
+ */
+pub fn parse() -> Cli {
+    let cli = Cli::parse();
+    let Command::Run {
+        profile,
+        config,
+        options,
+    } = &cli.command
+    else {
+        return cli;
+    };
+    let mut args: Vec<String> = vec![std::env::args().next().unwrap_or_default()];
+    match config::expand(profile, config.as_deref(), options) {
+        Ok(x) => args.extend(x),
+        Err(e) => fail(ErrorKind::InvalidValue, format!("{}: {}", profile, e)),
+    }
+    let mut job = Cli::parse_from(args);
+    if cli.lang.is_some() {
+        job.lang = cli.lang; // run の前の --lang はプロファイルより優先する
+    }
+    if let Command::Copy(_) | Command::Move(_) | Command::Rename(_) = job.command {
+        job
+    } else {
+        fail(ErrorKind::InvalidValue, format!("{}: not a job", profile));
+    }
+}
+
+// エラーを表示して終了する
+pub fn fail(kind: ErrorKind, message: String) -> ! {
+    Cli::command().error(kind, message).exit()
+}
+
+const RE_THREAD: &str = r"^(\d+)(/(\d+))?$"; // スレッド数 (small/large)
+const RE_DEVICE: &str = r"^((.*)=)?(\d+)$"; // デバイスごとの同時実行数
+const RE_BANDWIDTH: &str = r"^(\d+)([KMG]?)(,(\d{1,2})-(\d{1,2}))?$"; // 帯域制限 10M,8-20
+
+fn parse_threads(arg: &str) -> Result<[i32; 2], String> {
+    lazy_static! {
+        static ref RE_TH: Regex = Regex::new(RE_THREAD).unwrap();
+    }
+    let caps = RE_TH.captures(arg).ok_or("expected N or N/M")?;
+    let small: i32 = caps[1].parse().map_err(|_| "too large")?;
+    if small == 0 {
+        return Err("at least one thread is required".to_string());
+    }
+    let large: i32 = match caps.get(3) {
+        Some(x) => x.as_str().parse().map_err(|_| "too large")?,
+        None => 0,
+    };
+    Ok([small, large])
+}
+
+fn parse_regex(arg: &str) -> Result<Regex, String> {
+    Regex::new(arg).map_err(|e| e.to_string())
+}
+
+fn parse_capacity(arg: &str) -> Result<usize, String> {
+    match arg.parse::<usize>() {
+        Ok(x) if x > 0 => Ok(x),
+        _ => Err("expected a positive number".to_string()),
+    }
+}
+
+fn parse_device(arg: &str) -> Result<(String, i32), String> {
+    lazy_static! {
+        static ref RE_DV: Regex = Regex::new(RE_DEVICE).unwrap();
+    }
+    let caps = RE_DV.captures(arg).ok_or("expected N or MOUNT=N")?;
+    let mount: String = caps
+        .get(2)
+        .map(|x| x.as_str())
+        .unwrap_or_default()
+        .to_string();
+    let limit: i32 = caps[3].parse().map_err(|_| "too large")?;
+    if !mount.is_empty() && !std::path::Path::new(&mount).exists() {
+        return Err(format!("{:?} does not exist", mount)); // マウントポイント
+    }
+    Ok((mount, limit))
+}
+
+// freedesktop のゴミ箱は Linux だけ (他の OS は DIR が必要)
+fn parse_trash(arg: &str) -> Result<String, String> {
+    if arg.is_empty() && !cfg!(target_os = "linux") {
+        return Err("DIR is required on this platform".to_string());
+    }
+    Ok(arg.to_string())
+}
+
+fn parse_bandwidth(arg: &str) -> Result<Bandwidth, String> {
+    lazy_static! {
+        static ref RE_BW: Regex = Regex::new(RE_BANDWIDTH).unwrap();
+    }
+    let caps = RE_BW.captures(arg).ok_or("expected RATE[K|M|G][,H-H]")?;
+    let unit: u64 = match &caps[2] {
+        "K" => 1024,
+        "M" => 1024 * 1024,
+        "G" => 1024 * 1024 * 1024,
+        _ => 1,
+    };
+    let rate: u64 = caps[1]
+        .parse::<u64>()
+        .ok()
+        .and_then(|x| x.checked_mul(unit))
+        .ok_or("too large")?;
+    if rate == 0 {
+        return Err("expected a positive rate".to_string());
+    }
+    let mut hours: Option<(u32, u32)> = None;
+    if let (Some(start), Some(end)) = (caps.get(4), caps.get(5)) {
+        let start: u32 = start.as_str().parse().unwrap();
+        let end: u32 = end.as_str().parse().unwrap();
+        if start > 24 || end > 24 {
+            return Err("hours must be 0-24".to_string());
+        }
+        hours = Some((start, end));
+    }
+    Ok(Bandwidth {
+        text: arg.to_string(),
+        rate,
+        hours,
+    })
+}
+
+#[cfg(test)]
+#[test]
+fn cli_test() {
+    Cli::command().debug_assert();
+    let cli = Cli::try_parse_from([
+        "copy-asyncstd",
+        "move",
+        "a",
+        "b",
+        "-j",
+        "8/2",
+        "--queue=largest",
+        "--device-limit=2",
+        "--device-limit=/=1",
+        "--bwlimit=10M,8-20",
+        "--json",
+        "--verify",
+        "--no-verify",
+        "--versions",
+        "--keep-versions=3",
+        "--snapshot",
+        "--dedupe=reflink",
+    ])
+    .unwrap();
+    let Command::Move(job) = cli.command else {
+        panic!("move");
+    };
+    assert_eq!([8, 2], job.threads);
+    assert_eq!(Queue::Largest, job.queue);
+    assert_eq!(vec![("".to_string(), 2), ("/".to_string(), 1)], job.devices);
+    let bw: Bandwidth = job.bwlimit.unwrap();
+    assert_eq!((10 * 1024 * 1024, Some((8, 20))), (bw.rate, bw.hours));
+    assert_eq!(Some(""), job.json.as_deref());
+    assert!(!job.verify);
+    assert_eq!((Some(Version::Tree), 3), (job.versions, job.keep_versions));
+    assert!(job.snapshot);
+    assert_eq!(Some(Sharing::Reflink), job.dedupe);
+
+    // 不正な入力はエラー
+    for args in [
+        vec!["copy", "a"],                        // DST がない
+        vec!["copy", "a", "b", "x"],              // 余分な引数
+        vec!["copy", "a", "b", "--algorithm=mx"], // 不明なアルゴリズム
+        vec!["copy", "a", "b", "--threads=0"],
+        vec!["copy", "a", "b", "--bwlimit=10X"],
+        vec!["copy", "a", "b", "--bwlimit=18446744073709551615G"], // オーバーフロー
+        vec!["copy", "a", "b", "--device-limit=/nonexistent=1"],   // 存在しないマウント
+        vec!["copy", "a", "b", "--exclude=("],
+        vec!["copy", "a", "b", "--unknown"],
+    ] {
+        let argv = std::iter::once("copy-asyncstd").chain(args.clone());
+        assert!(Cli::try_parse_from(argv).is_err(), "{:?}", args);
+    }
+}
@@ -0,0 +1,186 @@
+/**
+ * Copyright (C) 2023 awk4j - https://ja.osdn.net/projects/awk4j/
+ * <p>
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ * <p>
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ * <p>
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{Error, Result};
+use std::path::{Path, PathBuf};
+
+/**
+ * Config - 設定ファイル (TOML) の名前付きプロファイル
+ *
+ * copy-asyncstd run <profile> [--config=<file>] [options...]
+ * プロファイルをコマンドライン引数に展開し、その後ろにコマンドラインのオプションを並べる
+ * 後ろのオプションが優先されるので、コマンドラインでプロファイルの値を上書きできる
+ *
+ * 設定ファイル: --config=<file>, ./copy-asyncstd.toml, ~/.config/copy-asyncstd/config.toml
+ */
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub source: Option<String>,      // 入力フォルダ
+    pub destination: Option<String>, // 出力フォルダ
+    pub mode: Option<String>,        // copy, move, rename
+    pub algorithm: Option<String>,   // std, maxbuf, channel
+    pub threads: Option<Threads>,    // 8 または "8/2"
+    #[serde(default)]
+    pub filters: Vec<String>, // 除外するパスの正規表現 (--exclude)
+    pub verify: Option<bool>,        // コピー後に出力を読み直して比較する (--verify)
+    #[serde(default)]
+    pub options: Vec<String>, // その他のオプション ("--queue=largest", "--manifest=...")
+}
+
+// スレッド数 - 数値または "small/large"
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Threads {
+    Number(i32),
+    Lanes(String),
+}
+
+#[derive(Debug, Deserialize)]
+struct Config {
+    #[serde(default)]
+    profiles: BTreeMap<String, Profile>,
+}
+
+const CONFIG_FILE: &str = "copy-asyncstd.toml";
+
+/**
+ * expand - プロファイルをコマンドライン引数に展開する
+ *
+ * -> [mode, source, destination, profile..., options...]
+ */
+pub fn expand(name: &str, config: Option<&str>, options: &[String]) -> Result<Vec<String>> {
+    let Some(path) = find(config) else {
+        return Err(Error::other(format!(
+            "config file not found: {}",
+            CONFIG_FILE
+        )));
+    };
+    let Some(profile) = load(&path)?.remove(name) else {
+        return Err(Error::other(format!("profile not found in {:?}", path)));
+    };
+    let mut args: Vec<String> = profile.to_args()?;
+    args.extend(options.iter().cloned());
+    Ok(args)
+}
+
+// 設定ファイルを探す
+fn find(config: Option<&str>) -> Option<PathBuf> {
+    if let Some(path) = config {
+        return Some(PathBuf::from(path));
+    }
+    let local: PathBuf = PathBuf::from(CONFIG_FILE);
+    if local.is_file() {
+        return Some(local);
+    }
+    let home: String = env::var("HOME").or_else(|_| env::var("USERPROFILE")).ok()?;
+    let user: PathBuf = Path::new(&home)
+        .join(".config")
+        .join("copy-asyncstd")
+        .join("config.toml");
+    user.is_file().then_some(user)
+}
+
+// 設定ファイルを読み込む -> プロファイル名 → プロファイル
+pub fn load(path: &Path) -> Result<BTreeMap<String, Profile>> {
+    parse(&fs::read_to_string(path)?)
+}
+
+fn parse(text: &str) -> Result<BTreeMap<String, Profile>> {
+    let config: Config = toml::from_str(text).map_err(Error::other)?;
+    Ok(config.profiles)
+}
+
+impl Profile {
+    // コマンドライン引数にする -> [mode, source, destination, options...]
+    pub fn to_args(&self) -> Result<Vec<String>> {
+        let (Some(source), Some(destination)) = (&self.source, &self.destination) else {
+            return Err(Error::other("source and destination are required"));
+        };
+        let mode: &str = self.mode.as_deref().unwrap_or("copy");
+        if !["copy", "move", "rename"].contains(&mode) {
+            return Err(Error::other(format!("mode: {:?}", mode)));
+        }
+        let mut args: Vec<String> = vec![mode.to_string(), source.clone(), destination.clone()];
+        if let Some(algorithm) = &self.algorithm {
+            args.push(format!("--algorithm={}", algorithm)); // clap が検査する
+        }
+        match &self.threads {
+            Some(Threads::Number(n)) => args.push(format!("--threads={}", n)),
+            Some(Threads::Lanes(x)) => args.push(format!("--threads={}", x)),
+            None => (),
+        }
+        for filter in &self.filters {
+            args.push(format!("--exclude={}", filter));
+        }
+        match self.verify {
+            Some(true) => args.push("--verify".to_string()),
+            Some(false) => args.push("--no-verify".to_string()),
+            None => (),
+        }
+        args.extend(self.options.iter().cloned());
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn profile_test() {
+    let profiles = parse(
+        r#"
+[profiles.music-backup]
+source = "D:/ミュージック"
+destination = "M:/ミュージック"
+mode = "move"
+algorithm = "maxbuf"
+threads = "8/2"
+filters = ['\.tmp$']
+verify = true
+options = ["--queue=largest"]
+
+[profiles.small]
+source = "a"
+destination = "b"
+threads = 4
+"#,
+    )
+    .unwrap();
+    let args: Vec<String> = profiles["music-backup"].to_args().unwrap();
+    assert_eq!(
+        vec![
+            "move",
+            "D:/ミュージック",
+            "M:/ミュージック",
+            "--algorithm=maxbuf",
+            "--threads=8/2",
+            r"--exclude=\.tmp$",
+            "--verify",
+            "--queue=largest",
+        ],
+        args
+    );
+    assert_eq!(
+        vec!["copy", "a", "b", "--threads=4"],
+        profiles["small"].to_args().unwrap()
+    );
+    assert!(parse("[profiles.x]\nsource = \"a\"\nunknown = 1\n").is_err());
+    let bad = parse("[profiles.x]\nsource = \"a\"\ndestination = \"b\"\nmode = \"mv\"\n").unwrap();
+    assert!(bad["x"].to_args().is_err());
+}
@@ -14,136 +14,403 @@
  * You should have received a copy of the GNU General Public License
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
-use std::sync::atomic::AtomicI32;
-use std::time::Duration;
+use blake3::Hasher;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::asyncmod;
-use crate::atomic;
+use crate::dashboard;
+use crate::features::Features;
 use crate::files;
-use crate::files::DD;
+use crate::files::{DD, EE};
+use crate::iomod;
+use crate::messages::tr;
 use crate::thmod;
+use crate::thmod::Scheduler;
+use crate::trash;
 
 // RustのTokioで非同期とグリーンスレッドを理解する
 // https://zenn.dev/tfutada/articles/5e87d6e7131e8e
 
 // https://doc.rust-jp.rs/book-ja/ch16-03-shared-state.html
 use async_std::task::JoinHandle;
-static mut HANDLES: Vec<JoinHandle<()>> = Vec::new();
-static THREADS: AtomicI32 = AtomicI32::new(0);
 
-pub fn set_threads(threads: i32) {
-    atomic::atomic_set(&THREADS, threads); // threads number
-}
-
-pub fn main() {
+pub fn main(sched: &Arc<Scheduler>, features: &Arc<Features>, input: &str, output: &str, ee: EE) {
     use async_std::task;
-    let _ = task::block_on(spawnx()); // 初期スレッドの起動
+    let features: Arc<Features> = features.clone();
+    task::block_on(spawnx(sched.clone(), features, input, output, ee)); // 初期スレッドの起動
 }
 // グリーンスレッド
-async fn spawnx() {
+async fn spawnx(sched: Arc<Scheduler>, features: Arc<Features>, input: &str, output: &str, ee: EE) {
     use async_std::task;
-    let threads: i32 = atomic::atomic_get(&THREADS);
-    for _ in 0..threads {
-        let handle: JoinHandle<()> = task::spawn(async {
-            loop {
-                let _pop = thmod::get(); // リクエストを取得
-                match _pop {
-                    Some(x) => task(x).await, // タスクを開始
-                    None => {
-                        if thmod::is_done() {
-                            break;
-                        } else {
-                            sleep().await;
-                        }
-                    }
-                };
-            }
-        });
-        unsafe {
-            HANDLES.push(handle); // スレッドハンドルを登録
+    let walker: Arc<Scheduler> = sched.clone();
+    let options: Arc<Features> = features.clone();
+    let (input, output) = (input.to_string(), output.to_string());
+    let walk: JoinHandle<()> = task::spawn(async move {
+        // panic しても走査を終える
+        let _eof = Terminator(walker.clone());
+        // リクエストを投げる (worker と並行)
+        let searched: io::Result<()> = if options.extractor.is_some() {
+            files::search_archive(&output, ee, walker.clone(), options).await // アーカイブのエントリー
+        } else {
+            files::search_fils(&input, &output, ee, walker.clone(), options).await
+        };
+        if let Err(e) = searched {
+            walker.error(format!("{}: {}", iomod::red(tr("search")), e));
+        }
+    });
+    sched.add_handle(walk); // スレッドハンドルを登録
+    let mut slot: usize = 0; // worker 番号
+    for lane in [thmod::SMALL, thmod::LARGE] {
+        for _ in 0..sched.threads(lane) {
+            spawn_worker(&sched, &features, lane, slot);
+            slot += 1;
         }
-        // _joinall().await; // spawn task で使用する
     }
-    waiting_for_completion().await; // 完了待ち
+    if sched.is_dashboard() {
+        sched.add_handle(task::spawn(dashboard::run(sched.clone()))); // 進捗の表示
+    }
+    joinall(&sched).await; // 完了待ち
 }
 
-// スレッドの完了を待ち合わせる (called from main)
-pub async fn _joinall() {
-    // use futures::future::join;
-    unsafe {
-        while HANDLES.len() > 0 {
-            let _handle: JoinHandle<()> = HANDLES.pop().unwrap();
-            // let _ = join(_handle);
-        }
+// 走査の終了 (EOF) を drop で必ず設定する、設定しないと worker が終わらない
+struct Terminator(Arc<Scheduler>);
+impl Drop for Terminator {
+    fn drop(&mut self) {
+        self.0.terminator();
     }
 }
 
-// 完了待ち
-pub async fn waiting_for_completion() {
-    while !thmod::is_done() {
-        sleep().await;
+// レーンの worker を起動する
+fn spawn_worker(sched: &Arc<Scheduler>, features: &Arc<Features>, lane: usize, slot: usize) {
+    use async_std::task;
+    let worker: Arc<Scheduler> = sched.clone();
+    let features: Arc<Features> = features.clone();
+    let handle: JoinHandle<()> = task::spawn(async move {
+        let mut idle = Idle::new();
+        loop {
+            let _pop = worker.get(lane); // リクエストを取得
+            match _pop {
+                Some(x) => {
+                    idle.reset();
+                    worker.begin(slot, &x);
+                    task(&worker, &features, slot, x).await; // タスクを開始
+                    worker.end(slot);
+                }
+                None => {
+                    if worker.is_done() {
+                        break;
+                    } else {
+                        idle.sleep().await;
+                    }
+                }
+            };
+        }
+    });
+    sched.add_handle(handle); // スレッドハンドルを登録
+}
+
+// スレッドの完了を待ち合わせる
+pub async fn joinall(sched: &Scheduler) {
+    while let Some(handle) = sched.take_handle() {
+        handle.await;
     }
 }
 
 // https://runebook.dev/ja/docs/rust/std/thread/fn.sleep
 // https://doc.rust-lang.org/1.64.0/std/thread/fn.sleep.html
 //
-static _IDLE: AtomicI32 = AtomicI32::new(0); // スリープ時間のもとになるカウンター
-static _DELAY: AtomicI32 = AtomicI32::new(0); // スリープする時間
+// スリープ時間はワーカーごとに持つ
+struct Idle {
+    idle: i32,  // スリープ時間のもとになるカウンター
+    delay: i32, // スリープする時間
+}
+impl Idle {
+    fn new() -> Idle {
+        Idle { idle: 0, delay: 1 }
+    }
+
+    fn reset(&mut self) {
+        self.idle = 0;
+        self.delay = 1;
+    }
 
-fn reset_sleep() {
-    atomic::atomic_set(&_IDLE, 0);
-    atomic::atomic_set(&_DELAY, 1);
+    async fn sleep(&mut self) {
+        use async_std::task;
+        if self.idle > 10_000 {
+            self.idle = 0;
+        }
+        if self.idle > 10 {
+            // 10回以上何もせずにループしていた場合、
+            // 次のループ以降のスリープ時間を2倍ずつ増やしていく
+            // このときに最大スリープ時間は10,000マイクロ秒としている(10ミリ秒)
+            self.delay = (self.delay * 2).min(10_000);
+        } else {
+            // ループのたびにidelをインクリメントする
+            // idelが10に満たないときはスリープ時間は一律で1,000マイクロ秒となる(1ミリ秒)
+            self.idle += 1;
+            self.delay = 1000;
+        }
+        // 指定されたマイクロ秒分だけスリープする
+        task::sleep(Duration::from_micros(self.delay as u64)).await;
+        // println!("IDLING: {} {} ", self.idle, self.delay);
+    }
 }
 
-async fn sleep() {
-    use async_std::task;
-    let mut idle: i32 = atomic::atomic_get(&_IDLE);
-    let mut delay: i32 = atomic::atomic_get(&_DELAY);
-    if idle > 10_000 {
-        idle = 0;
-    }
-    if idle > 10 {
-        // 10回以上何もせずにループしていた場合、
-        // 次のループ以降のスリープ時間を2倍ずつ増やしていく
-        // このときに最大スリープ時間は10,000マイクロ秒としている(10ミリ秒)
-        delay = (delay * 2).min(10_000);
+async fn task(sched: &Scheduler, features: &Features, slot: usize, dd: DD) {
+    log::trace!("task: {}", dd.input);
+    let input: &String = &dd.input.clone();
+    let start: Instant = Instant::now();
+    let dedupe: bool = features.dedupe.is_some() && dd.action != files::SKIP;
+    let hashing: bool = features.manifest.is_some() || must_verify(features, &dd) || dedupe;
+    let hashing: bool = hashing || features.finder.is_some();
+    let mut hasher: Option<Hasher> = hashing.then(Hasher::new);
+    let mut rs: io::Result<u64> = if let (Some(_), Some(h)) = (&features.finder, hasher.as_mut()) {
+        hash_input(sched, slot, input, h).await // dupes (出力は書かない)
+    } else if dd.action != files::SKIP {
+        // 上書きする出力ファイルを世代に残すか隔離してからアクションを実行
+        let kept = backup(features, &dd.output)
+            .and_then(|_| quarantine(features, &dd.output, trash::OUTPUT));
+        match kept {
+            Ok(()) => execute(sched, features, slot, &dd, hasher.as_mut()).await,
+            Err(e) => Err(e),
+        }
+    } else if let Some(h) = hasher.as_mut() {
+        asyncmod::hash_file(&dd.output, h).await.map(|_| 0) // 既存の出力ファイル
     } else {
-        // ループのたびにidelをインクリメントする
-        // idelが10に満たないときはスリープ時間は一律で1,000マイクロ秒となる(1ミリ秒)
-        idle += 1;
-        delay = 1000;
-    }
-    atomic::atomic_set(&_IDLE, idle);
-    atomic::atomic_set(&_DELAY, delay);
-    // 指定されたマイクロ秒分だけスリープする
-    task::sleep(Duration::from_micros(delay as u64)).await;
-    // println!("IDLING: {} {} ", idle, delay);
+        Ok(0)
+    };
+    if let (Ok(_), Some(h)) = (&rs, &hasher) {
+        if must_verify(features, &dd) {
+            if let Err(e) = verify(&dd, h).await {
+                rs = Err(e); // 入力ファイルは削除しない
+            }
+        }
+    }
+    match rs {
+        Ok(length) => {
+            if let (Some(manifest), Some(h)) = (&features.manifest, &hasher) {
+                manifest.record(&dd, &h.finalize().to_hex());
+            }
+            if let (Some(dedupe), Some(h), true) = (&features.dedupe, &hasher, dedupe) {
+                dedupe.add(&dd.output, dd.size, h.finalize()); // 次の重複の候補
+            }
+            if let (Some(finder), Some(h)) = (&features.finder, &hasher) {
+                finder.record(input, dd.size, h.finalize()); // 重複の候補
+            }
+            let moving: bool = dd.cmr_mode == files::_MOVE || dd.cmr_mode == files::_RENAME;
+            if moving && dd.action != files::SKIP {
+                // 検証済みの入力ファイルだけを削除 (Move, Rename)
+                let removed: io::Result<()> = match features.trash {
+                    Some(_) => quarantine(features, input, trash::INPUT),
+                    None => asyncmod::remove_file(input).await,
+                };
+                match removed {
+                    Ok(()) => log::info!("delete: {}", input),
+                    Err(e) => sched.error(format!("{}: {} {}", iomod::red(tr("delete")), input, e)),
+                }
+            }
+            sched.completed(&dd, length, start.elapsed());
+        }
+        Err(e) => sched.failed(&dd, &e), // 入力ファイルは残す
+    }
+    sched.release(&dd); // デバイスの解放
+    sched.progress(input); // プログレス
 }
 
-async fn task(dd: DD) -> () {
-    // println!("task: {}", dd.input); ////
-    reset_sleep();
-    let input: &String = &dd.input.clone();
-    let output: &String = &dd.output.clone();
-    if dd.action != files::SKIP {
-        // スキップ以外ならアクションを実行
-        if dd.cmr_mode == files::_RENAME {
-            asyncmod::rename_file(input, output).await; // Rename
-        } else if dd.algorithm == files::_STD {
-            asyncmod::copy(input, output).await; // Copy, Move
-        } else if dd.algorithm == files::_MAXBUF {
-            asyncmod::copymax(input, output).await; // maxbuf
-        } else if dd.algorithm == files::_TEST {
-            let _ = async_std::fs::copy(input, output).await; // Test
-            println!("exit test: {}", input); ////
-        } else {
-            asyncmod::copych(input, output).await; // channel
+// 入力のハッシュだけを計算する (dupes) -> length
+async fn hash_input(
+    sched: &Scheduler,
+    slot: usize,
+    input: &str,
+    hasher: &mut Hasher,
+) -> io::Result<u64> {
+    let length: u64 = asyncmod::hash_file(input, hasher).await?;
+    sched.transferred(slot, length as usize).await;
+    Ok(length)
+}
+
+// 上書きする出力ファイルを世代として残す (--versions)
+fn backup(features: &Features, path: &str) -> io::Result<()> {
+    let path: &Path = Path::new(path);
+    if let (Some(versions), true) = (&features.versions, path.is_file()) {
+        versions.backup(path)?;
+    }
+    Ok(())
+}
+
+// 既存のファイルを隔離する (--trash)
+fn quarantine(features: &Features, path: &str, kind: &str) -> io::Result<()> {
+    let path: &Path = Path::new(path);
+    if let (Some(trash), true) = (&features.trash, path.is_file()) {
+        trash.put(path, kind)?;
+    }
+    Ok(())
+}
+
+// 出力を検証するか (--verify, move は常に検証してから入力を削除する)
+// スキップとリンクは同じ内容なので検証しない
+// rename は同じファイルなので検証しない (別のファイルシステムは execute が検証する)
+fn must_verify(features: &Features, dd: &DD) -> bool {
+    let verify: bool = features.verify || dd.cmr_mode == files::_MOVE;
+    verify && dd.action == files::DO && dd.cmr_mode != files::_RENAME
+}
+
+// 出力ファイルを読み直してコピー中のハッシュと比較する (--verify)
+async fn verify(dd: &DD, hasher: &Hasher) -> io::Result<()> {
+    let mut check = Hasher::new();
+    asyncmod::hash_file(&dd.output, &mut check).await?;
+    if check.finalize() != hasher.finalize() {
+        return Err(io::Error::other("verify: hash mismatch"));
+    }
+    Ok(())
+}
+
+// アクションの実行 -> length
+//
+// hasher があればコピーしながらハッシュを計算する (rename, link, test は出力を読み直す)
+async fn execute(
+    sched: &Scheduler,
+    features: &Features,
+    slot: usize,
+    dd: &DD,
+    mut hasher: Option<&mut Hasher>,
+) -> io::Result<u64> {
+    let input: &String = &dd.input;
+    let output: &String = &dd.output;
+    if iomod::is_shared(output) {
+        // ハードリンクを共有している出力ファイル (--dedupe, --snapshot) は作り直す
+        // fs::copy, File::create は truncate するので、他のリンクの内容まで書き換えてしまう
+        asyncmod::remove_file(output).await?;
+    }
+    if dd.action == files::LINK {
+        // 変わっていないファイルは前回のスナップショットからハードリンクする
+        let previous: Option<PathBuf> = features
+            .snapshot
+            .as_ref()
+            .and_then(|x| x.link_source(Path::new(output)));
+        if let Some(previous) = previous {
+            match async_std::fs::hard_link(&previous, output).await {
+                Ok(()) => {
+                    if let Some(h) = hasher.as_deref_mut() {
+                        asyncmod::hash_file(output, h).await?;
+                    }
+                    return Ok(0);
+                }
+                Err(e) => log::debug!("link: {}, copy: {}", e, input), // リンクできなければコピー
+            }
+        }
+    }
+    if let Some(extractor) = &features.extractor {
+        // アーカイブのエントリーを取り出す
+        let length: u64 = extractor.extract(input, output).await?;
+        if let Some(h) = hasher {
+            asyncmod::hash_file(output, h).await?;
+        }
+        sched.transferred(slot, length as usize).await;
+        return Ok(length);
+    }
+    if let (Some(dedupe), false) = (&features.dedupe, dd.cmr_mode == files::_RENAME) {
+        // リンクを共有している出力ファイルを書き換えないように先に削除する
+        asyncmod::remove_file(output).await?;
+        let mut local = Hasher::new(); // 入力のハッシュ
+        if let Some(existing) = dedupe.find(input, output, dd.size, &mut local).await {
+            let expected = local.finalize();
+            match dedupe
+                .link(&existing, Path::new(output), dd.size, expected)
+                .await
+            {
+                Ok(()) => {
+                    if let Some(h) = hasher.as_deref_mut() {
+                        *h = local; // --verify は入力と出力を比べる
+                    }
+                    return Ok(0);
+                }
+                Err(e) => log::debug!("dedupe: {}, copy: {}", e, input), // リンクできなければコピー
+            }
         }
     }
-    if dd.cmr_mode != files::_COPY {
-        // 入力ファイルを削除
-        let _ = asyncmod::remove_file(input).await; // Move, Rename
+    let is_plain: bool = !sched.throttle().is_active() && hasher.is_none();
+    if dd.cmr_mode == files::_RENAME {
+        match asyncmod::rename_file(input, output).await {
+            Ok(()) => {
+                if let Some(h) = hasher {
+                    asyncmod::hash_file(output, h).await?;
+                }
+                sched.transferred(slot, dd.size as usize).await;
+                Ok(dd.size)
+            }
+            // 別のファイルシステム (EXDEV) はコピーして検証する (入力は task が削除する)
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                log::debug!("rename: {}, copy: {}", e, input);
+                let mut local = Hasher::new();
+                let h: &mut Hasher = hasher.unwrap_or(&mut local);
+                let length: u64 = asyncmod::copymax(input, output, sched, slot, Some(h)).await?;
+                verify(dd, h).await?;
+                asyncmod::copy_attributes(input, output)?; // mv と同じく日時と権限を残す
+                Ok(length)
+            }
+            Err(e) => Err(e),
+        }
+    } else if dd.algorithm == files::_STD && is_plain {
+        asyncmod::copy_progress(input, output, sched, slot).await // Copy, Move
+    } else if dd.algorithm == files::_STD || dd.algorithm == files::_MAXBUF {
+        // 帯域制限中とハッシュの計算中は std も maxbuf でコピーする
+        asyncmod::copymax(input, output, sched, slot, hasher).await // maxbuf
+    } else if dd.algorithm == files::_TEST {
+        let length: u64 = async_std::fs::copy(input, output).await?; // Test
+        if let Some(h) = hasher {
+            asyncmod::hash_file(output, h).await?;
+        }
+        sched.transferred(slot, length as usize).await;
+        println!("exit test: {}", input); ////
+        Ok(length)
+    } else {
+        asyncmod::copych(input, output, sched, slot, hasher).await // channel
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn move_test() {
+    use std::fs;
+    let root = iomod::TempDir::new("move_test");
+    let (input, output) = (root.join("in"), root.join("out"));
+    fs::create_dir_all(input.join("a")).unwrap();
+    fs::create_dir_all(output.join("a/failed.txt")).unwrap(); // 同じ名前のフォルダで失敗する
+    for name in ["a/moved.txt", "a/failed.txt", "skip.txt"] {
+        fs::write(input.join(name), name).unwrap();
     }
-    thmod::progress(input); // プログレス
+    fs::write(output.join("skip.txt"), "skip.txt").unwrap();
+    let newer = std::time::SystemTime::now() + Duration::from_secs(3600);
+    let file = fs::File::options()
+        .write(true)
+        .open(output.join("skip.txt"))
+        .unwrap();
+    file.set_modified(newer).unwrap(); // 出力側が新しい
+    let mut sched = Scheduler::new(thmod::_FIFO, 16, [2, 0]);
+    sched.set_quiet(true);
+    let sched = Arc::new(sched);
+    let ee = EE {
+        cmr_mode: files::_MOVE,
+        algorithm: files::_STD,
+    };
+    main(
+        &sched,
+        &Arc::new(Features::default()),
+        &iomod::path_to_string(&input),
+        &iomod::path_to_string(&output),
+        ee,
+    );
+    // 検証済みの入力だけを削除し、失敗とスキップの入力は残す
+    assert_eq!(
+        "a/moved.txt",
+        fs::read_to_string(output.join("a/moved.txt")).unwrap()
+    );
+    assert!(!input.join("a/moved.txt").exists());
+    assert!(input.join("a/failed.txt").is_file());
+    assert!(input.join("skip.txt").is_file());
+    assert_eq!(1, sched.totals().1);
 }
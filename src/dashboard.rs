@@ -0,0 +1,98 @@
+/**
+ * Copyright (C) 2023 awk4j - https://ja.osdn.net/projects/awk4j/
+ * <p>
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ * <p>
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ * <p>
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::iomod;
+use crate::thmod;
+use crate::thmod::{Scheduler, Slot};
+
+/**
+ * Dashboard - 端末に worker ごとの進捗を複数行で表示する
+ *
+ * 全体の進捗バー、worker ごとに1行 (ファイル名, バイト数, 速度)、最近のエラー
+ * 前回の表示行数だけカーソルを戻して上書きする
+ * 端末でなければ起動しない (thmod::progress が1ファイル1行で出力する)
+ */
+pub async fn run(sched: Arc<Scheduler>) {
+    use async_std::task;
+    let mut lines: usize = 0; // 前回の表示行数
+    while !sched.is_done() {
+        lines = render(&sched, lines);
+        task::sleep(Duration::from_millis(REFRESH)).await;
+    }
+    render(&sched, lines);
+}
+
+const REFRESH: u64 = 200; // 再描画の間隔 (ミリ秒)
+const BAR_WIDTH: usize = 30; // 進捗バーの幅
+
+// 描画して表示した行数を返す
+fn render(sched: &Scheduler, lines: usize) -> usize {
+    let mut screen: Vec<String> = Vec::new();
+    let ratio: f64 = sched.ratio();
+    let fill: usize = (ratio * BAR_WIDTH as f64) as usize;
+    let bar: String = format!("{}{}", "#".repeat(fill), "-".repeat(BAR_WIDTH - fill));
+    let (done, seq) = sched.counts();
+    let status: String = format!(
+        "{} {}/{} {}",
+        sched.elapsed_time(),
+        done,
+        seq,
+        sched.bytes_status()
+    );
+    screen.push(format!("[{}] {}", bar, iomod::cyan(status)));
+    for (i, slot) in sched.slots().iter().enumerate() {
+        screen.push(format!(" #{:<2} {}", i + 1, slot_line(slot)));
+    }
+    for error in sched.errors() {
+        screen.push(format!(" {}", iomod::red(error)));
+    }
+    let mut out = std::io::stdout().lock();
+    if lines > 0 {
+        // \e[nA 上にn移動, \e[J カーソルより後ろを消去
+        let _ = write!(out, "\x1b[{}A\x1b[J", lines);
+    }
+    for line in &screen {
+        let _ = writeln!(out, "{}\x1b[K", line);
+    }
+    let _ = out.flush();
+    screen.len()
+}
+
+// worker の1行 - バイト数, 速度, ファイル名
+fn slot_line(slot: &Option<Slot>) -> String {
+    match slot {
+        None => "-".to_string(), // idle
+        Some(x) => {
+            let secs: f64 = x.start.elapsed().as_secs_f64();
+            let mbps: f64 = if secs > 0.0 {
+                x.done as f64 / thmod::MB / secs
+            } else {
+                0.0
+            };
+            format!(
+                "{:>8.1}/{:<8.1}MB {:>6.1}MB/s {}",
+                x.done as f64 / thmod::MB,
+                x.size as f64 / thmod::MB,
+                mbps,
+                thmod::pack_path(&x.input)
+            )
+        }
+    }
+}
@@ -0,0 +1,254 @@
+/**
+ * Copyright (C) 2023 awk4j - https://ja.osdn.net/projects/awk4j/
+ * <p>
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ * <p>
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ * <p>
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+use blake3::{Hash, Hasher};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, Result};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, AtomicU64};
+use std::sync::Mutex;
+
+use crate::asyncmod;
+use crate::atomic;
+use crate::iomod;
+
+/**
+ * Dedupe - 出力フォルダの重複排除 (--dedupe[=link|reflink])
+ *
+ * 開始時に出力フォルダのファイルをサイズごとに一覧にしておき、
+ * 同じサイズのファイルがあるときだけ入力と候補のハッシュを比べる
+ * 同じ内容があればコピーせずにハードリンク (reflink) を作る
+ * コピーしたファイルはコピー中に計算したハッシュで一覧に加える
+ */
+pub const _LINK: char = 'l';
+pub const _REFLINK: char = 'r';
+
+pub struct Dedupe {
+    method: char,                             // _LINK, _REFLINK
+    sizes: Mutex<HashMap<u64, Vec<PathBuf>>>, // サイズ -> 出力ファイル
+    hashes: Mutex<HashMap<PathBuf, Hash>>,    // 計算済みのハッシュ
+    linked: AtomicI32,                        // リンクしたファイル数
+    saved: AtomicU64,                         // 節約したバイト数
+}
+
+impl Dedupe {
+    pub fn new(method: char, output: &str) -> Dedupe {
+        let mut sizes: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+        index(Path::new(output), &mut sizes);
+        Dedupe {
+            method,
+            sizes: Mutex::new(sizes),
+            hashes: Mutex::new(HashMap::new()),
+            linked: AtomicI32::new(0),
+            saved: AtomicU64::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        if self.method == _REFLINK {
+            "reflink"
+        } else {
+            "link"
+        }
+    }
+
+    // 入力と同じ内容の出力ファイルを探す (output 自身は除く)
+    // 候補があれば hasher に入力のハッシュを計算する (--verify はこれと出力を比べる)
+    pub async fn find(
+        &self,
+        input: &str,
+        output: &str,
+        size: u64,
+        hasher: &mut Hasher,
+    ) -> Option<PathBuf> {
+        if size == 0 {
+            return None; // 空のファイルはリンクしない
+        }
+        let candidates: Vec<PathBuf> = self.sizes.lock().unwrap().get(&size)?.clone();
+        let candidates: Vec<PathBuf> = candidates
+            .into_iter()
+            .filter(|x| x.as_path() != Path::new(output))
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        asyncmod::hash_file(input, hasher).await.ok()?;
+        let hash: Hash = hasher.finalize();
+        for candidate in candidates {
+            let cached: Option<Hash> = self.hashes.lock().unwrap().get(&candidate).copied();
+            let other: Hash = match cached {
+                Some(x) => x,
+                None => match hash_of(&iomod::path_to_string(&candidate)).await {
+                    Ok(x) => {
+                        self.hashes.lock().unwrap().insert(candidate.clone(), x);
+                        x
+                    }
+                    Err(_) => continue, // 削除された候補など
+                },
+            };
+            if other == hash {
+                return Some(candidate);
+            }
+        }
+        None
+    }
+
+    // 同じ内容のファイルをリンクする (expected は入力のハッシュ)
+    pub async fn link(
+        &self,
+        existing: &Path,
+        output: &Path,
+        size: u64,
+        expected: Hash,
+    ) -> Result<()> {
+        if self.method == _REFLINK {
+            reflink(existing, output)?;
+        } else {
+            fs::hard_link(existing, output)?;
+        }
+        // 比べた後で他の worker が候補を書き換えていないか、リンクした内容を確かめる
+        if hash_of(&iomod::path_to_string(output)).await? != expected {
+            let _ = fs::remove_file(output);
+            return Err(Error::other("dedupe: candidate changed"));
+        }
+        atomic::atomic_add(&self.linked, 1);
+        atomic::atomic_add64(&self.saved, size);
+        log::info!("dedupe: {:?} -> {:?}", existing, output);
+        Ok(())
+    }
+
+    // コピーしたファイルを一覧に加える
+    pub fn add(&self, output: &str, size: u64, hash: Hash) {
+        if size == 0 {
+            return;
+        }
+        let output: PathBuf = PathBuf::from(output);
+        let mut sizes = self.sizes.lock().unwrap();
+        let paths: &mut Vec<PathBuf> = sizes.entry(size).or_default();
+        if !paths.contains(&output) {
+            paths.push(output.clone());
+        }
+        self.hashes.lock().unwrap().insert(output, hash);
+    }
+
+    // (リンクしたファイル数, 節約したバイト数)
+    pub fn saved(&self) -> (i32, u64) {
+        (
+            atomic::atomic_get(&self.linked),
+            atomic::atomic_get64(&self.saved),
+        )
+    }
+}
+
+// 出力フォルダのファイルをサイズごとに集める (シンボリックリンクはたどらない)
+pub fn index(dir: &Path, sizes: &mut HashMap<u64, Vec<PathBuf>>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            index(&entry.path(), sizes);
+        } else if file_type.is_file() {
+            let size: u64 = entry.metadata().map(|x| x.len()).unwrap_or(0);
+            if size > 0 {
+                sizes.entry(size).or_default().push(entry.path());
+            }
+        }
+    }
+}
+
+async fn hash_of(path: &str) -> Result<Hash> {
+    let mut hasher = Hasher::new();
+    asyncmod::hash_file(path, &mut hasher).await?;
+    Ok(hasher.finalize())
+}
+
+// ファイルシステムの共有コピー (Linux の FICLONE, Btrfs, XFS など)
+#[cfg(target_os = "linux")]
+fn reflink(from: &Path, to: &Path) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    const FICLONE: libc::c_ulong = 0x4004_9409;
+    let src = fs::File::open(from)?;
+    let dst = fs::File::create(to)?;
+    // SAFETY: どちらも開いているファイルディスクリプタ
+    let rc: i32 = unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE as _, src.as_raw_fd()) };
+    if rc != 0 {
+        let e: Error = Error::last_os_error();
+        drop(dst);
+        let _ = fs::remove_file(to);
+        return Err(e);
+    }
+    Ok(())
+}
+#[cfg(not(target_os = "linux"))]
+fn reflink(_from: &Path, _to: &Path) -> Result<()> {
+    Err(Error::new(std::io::ErrorKind::Unsupported, "reflink"))
+}
+
+#[cfg(test)]
+#[test]
+fn dedupe_test() {
+    let root = iomod::TempDir::new("dedupe_test");
+    fs::create_dir_all(root.join("out/a")).unwrap();
+    fs::create_dir_all(root.join("in")).unwrap();
+    fs::write(root.join("out/a/x.flac"), "album").unwrap();
+    fs::write(root.join("out/a/y.flac"), "other").unwrap(); // 同じサイズで別の内容
+    fs::write(root.join("in/x.flac"), "album").unwrap();
+    fs::write(root.join("in/z.flac"), "new!!").unwrap();
+    let dedupe = Dedupe::new(_LINK, &iomod::path_to_string(root.join("out")));
+    let input = |x: &str| iomod::path_to_string(root.join("in").join(x));
+    let output: String = iomod::path_to_string(root.join("out/b/x.flac"));
+    let mut hasher = Hasher::new();
+    async_std::task::block_on(async {
+        let found: Option<PathBuf> = dedupe.find(&input("x.flac"), &output, 5, &mut hasher).await;
+        assert_eq!(Some(root.join("out/a/x.flac")), found);
+        let mut other = Hasher::new();
+        assert_eq!(
+            None,
+            dedupe.find(&input("z.flac"), &output, 5, &mut other).await
+        );
+        // output 自身とは比べない
+        let itself: String = iomod::path_to_string(root.join("out/a/x.flac"));
+        assert_eq!(
+            None,
+            dedupe
+                .find(&input("x.flac"), &itself, 5, &mut other.clone())
+                .await
+        );
+
+        // リンクした内容が入力と違えばリンクしない
+        fs::create_dir_all(root.join("out/b")).unwrap();
+        let existing: PathBuf = root.join("out/a/x.flac");
+        let changed: Hash = blake3::hash(b"other");
+        assert!(dedupe
+            .link(&existing, Path::new(&output), 5, changed)
+            .await
+            .is_err());
+        assert!(!Path::new(&output).exists());
+        let expected: Hash = hasher.finalize();
+        dedupe
+            .link(&existing, Path::new(&output), 5, expected)
+            .await
+            .unwrap();
+    });
+    assert_eq!(blake3::hash(b"album"), hasher.finalize()); // 入力のハッシュ
+    assert_eq!("album", fs::read_to_string(&output).unwrap());
+    assert_eq!((1, 5), dedupe.saved());
+}
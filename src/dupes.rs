@@ -0,0 +1,327 @@
+/**
+ * Copyright (C) 2023 awk4j - https://ja.osdn.net/projects/awk4j/
+ * <p>
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ * <p>
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ * <p>
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+use blake3::Hash;
+use clap::error::ErrorKind;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Error, Result};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::cli;
+use crate::cli::Dupes;
+use crate::daemon;
+use crate::features::Features;
+use crate::files;
+use crate::files::{DD, EE};
+use crate::iomod;
+use crate::messages::{tr, trf};
+use crate::thmod;
+use crate::thmod::Scheduler;
+
+/**
+ * dupes - 重複ファイルの検出 (dupes <DIR> [--link])
+ *
+ * 1. 走査 (files::search_fils) が見つけたファイルをサイズで分け、同じサイズが複数あるファイルだけを
+ * 2. worker でハッシュを計算し (files::_HASH)
+ * 3. 同じハッシュのグループと無駄な容量を表示する
+ *
+ * --link は各グループの最初のファイル (パス順) へのハードリンクに置き換える
+ * 置き換える直前にハッシュを計算し直し、変更されたファイルは置き換えない
+ */
+pub struct Finder {
+    pending: Mutex<HashMap<u64, Option<DD>>>, // サイズ -> 保留中の最初のファイル
+    hashes: Mutex<Vec<(u64, Hash, PathBuf)>>, // サイズ, ハッシュ, パス
+}
+
+// 同じ内容のファイル
+#[derive(Debug)]
+struct Group {
+    size: u64,           // ファイルサイズ
+    hash: Hash,          // 内容のハッシュ
+    paths: Vec<PathBuf>, // パス順
+    wasted: u64,         // 無駄な容量 (すでにハードリンクのものは数えない)
+}
+
+impl Finder {
+    pub fn new() -> Finder {
+        Finder {
+            pending: Mutex::new(HashMap::new()),
+            hashes: Mutex::new(Vec::new()),
+        }
+    }
+
+    // 走査したファイル -> ハッシュを計算するリクエスト
+    // サイズごとに最初のファイルは保留し、同じサイズの2つ目が来たら一緒に返す
+    pub fn seen(&self, dd: DD) -> Vec<DD> {
+        if dd.size == 0 {
+            return Vec::new(); // 空のファイルは数えない
+        }
+        match self.pending.lock().unwrap().entry(dd.size) {
+            Entry::Vacant(x) => {
+                x.insert(Some(dd));
+                Vec::new()
+            }
+            Entry::Occupied(mut x) => match x.get_mut().take() {
+                Some(first) => vec![first, dd],
+                None => vec![dd],
+            },
+        }
+    }
+
+    pub fn record(&self, path: &str, size: u64, hash: Hash) {
+        let mut hashes = self.hashes.lock().unwrap();
+        hashes.push((size, hash, PathBuf::from(path)));
+    }
+
+    // 同じハッシュのグループ (無駄な容量の大きい順)
+    fn groups(&self) -> Vec<Group> {
+        let mut map: HashMap<(u64, [u8; 32]), Vec<PathBuf>> = HashMap::new();
+        for (size, hash, path) in self.hashes.lock().unwrap().iter() {
+            map.entry((*size, *hash.as_bytes()))
+                .or_default()
+                .push(path.clone());
+        }
+        let mut groups: Vec<Group> = map
+            .into_iter()
+            .filter(|(_, paths)| paths.len() > 1)
+            .map(|((size, hash), mut paths)| {
+                paths.sort();
+                let files: usize = distinct(&paths);
+                Group {
+                    size,
+                    hash: Hash::from(hash),
+                    paths,
+                    wasted: size * (files as u64 - 1),
+                }
+            })
+            .filter(|x| x.wasted > 0) // すべて同じ実体のグループは除く
+            .collect();
+        groups.sort_by(|a, b| b.wasted.cmp(&a.wasted).then(a.paths.cmp(&b.paths)));
+        groups
+    }
+}
+
+// 実体の数 (同じ inode は1つと数える)
+fn distinct(paths: &[PathBuf]) -> usize {
+    let mut ids: HashSet<(u64, u64)> = HashSet::new();
+    let mut unknown: usize = 0;
+    for path in paths {
+        match file_id(path) {
+            Some(id) => {
+                ids.insert(id);
+            }
+            None => unknown += 1,
+        }
+    }
+    ids.len() + unknown
+}
+
+#[cfg(unix)]
+fn file_id(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    fs::symlink_metadata(path).ok().map(|x| (x.dev(), x.ino())) // リンク先をたどらない
+}
+#[cfg(not(unix))]
+fn file_id(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+// ハッシュを計算したときと同じ内容か (サイズとハッシュを比べる)
+fn unchanged(path: &Path, group: &Group) -> Result<bool> {
+    let mut file = fs::File::open(path)?;
+    if file.metadata()?.len() != group.size {
+        return Ok(false);
+    }
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize() == group.hash)
+}
+
+// 重複をハードリンクに置き換える (一時ファイルにリンクしてから rename) -> 置き換えたか
+// keep は呼び出し側で確認済み、duplicate は rename の直前に確認する
+fn replace_with_link(keep: &Path, duplicate: &Path, group: &Group) -> Result<bool> {
+    let regular = |x: &Path| fs::symlink_metadata(x).is_ok_and(|x| x.is_file());
+    if !regular(keep) || !regular(duplicate) {
+        return Ok(false); // シンボリックリンクは置き換えない
+    }
+    if file_id(keep).is_some() && file_id(keep) == file_id(duplicate) {
+        return Ok(false); // すでに同じ実体
+    }
+    let name: String = format!(".{}.dupes", iomod::get_filename(duplicate));
+    let temp: PathBuf = duplicate.with_file_name(name);
+    fs::hard_link(keep, &temp)?;
+    let replaced: Result<()> = match unchanged(duplicate, group) {
+        Ok(true) => fs::rename(&temp, duplicate),
+        Ok(false) => Err(Error::other(tr("dupes_changed"))),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = replaced {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    log::info!("dupes: link {:?} -> {:?}", keep, duplicate);
+    Ok(true)
+}
+
+/**
+ * main - 重複の表示 (--link で置き換え)
+ */
+pub fn main(args: &Dupes) {
+    let dir: &Path = Path::new(&args.dir);
+    if !dir.is_dir() {
+        let message = trf("not_folder", &[&format!("{:?}", args.dir)]);
+        cli::fail(ErrorKind::ValueValidation, message);
+    }
+    println!(
+        " {}: {}",
+        iomod::blue(tr("input_folder")),
+        iomod::path_to_unix(dir)
+    );
+    let sched = Arc::new(Scheduler::new(thmod::_FIFO, 2048, args.threads));
+    let features = Arc::new(Features {
+        finder: Some(Finder::new()),
+        ..Features::default()
+    });
+    let ee = EE {
+        cmr_mode: files::_HASH,
+        algorithm: files::_STD,
+    };
+    daemon::main(&sched, &features, &args.dir, &args.dir, ee); // 入力フォルダ自身を出力として渡す
+    println!();
+    sched.progress_fin(tr("finished"), &[]);
+
+    let groups: Vec<Group> = features
+        .finder
+        .as_ref()
+        .map(|x| x.groups())
+        .unwrap_or_default();
+    let mut duplicates: usize = 0;
+    let mut wasted: u64 = 0;
+    let mut linked: usize = 0;
+    for group in &groups {
+        let size: String = format!("{:.1}MB", group.size as f64 / thmod::MB);
+        let waste: String = format!("{:.1}MB", group.wasted as f64 / thmod::MB);
+        println!(
+            "{}",
+            iomod::cyan(trf("dupes_group", &[&size, &group.paths.len(), &waste]))
+        );
+        for path in &group.paths {
+            let relative: &Path = path.strip_prefix(dir).unwrap_or(path);
+            println!("    {}", iomod::path_to_unix(relative));
+        }
+        duplicates += group.paths.len() - 1;
+        wasted += group.wasted;
+        if args.link {
+            let keep: &Path = &group.paths[0];
+            match unchanged(keep, group) {
+                Ok(true) => (),
+                Ok(false) => {
+                    eprintln!(
+                        "{}: {:?} {}",
+                        iomod::red(tr("failed")),
+                        keep,
+                        tr("dupes_changed")
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("{}: {:?} {}", iomod::red(tr("failed")), keep, e);
+                    continue;
+                }
+            }
+            for duplicate in &group.paths[1..] {
+                match replace_with_link(keep, duplicate, group) {
+                    Ok(true) => linked += 1,
+                    Ok(false) => (),
+                    Err(e) => eprintln!("{}: {:?} {}", iomod::red(tr("failed")), duplicate, e),
+                }
+            }
+        }
+    }
+    let wasted: String = format!("{:.1}", wasted as f64 / thmod::MB);
+    println!(
+        "{}",
+        trf("dupes_total", &[&groups.len(), &duplicates, &wasted])
+    );
+    if args.link {
+        println!("{}", iomod::cyan(trf("dupes_linked", &[&linked])));
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn dupes_test() {
+    let root = iomod::TempDir::new("dupes_test");
+    fs::create_dir_all(root.join("a")).unwrap();
+    fs::write(root.join("a/1.flac"), "album").unwrap();
+    fs::write(root.join("a/2.flac"), "album").unwrap();
+    fs::write(root.join("3.flac"), "album").unwrap();
+    fs::write(root.join("other"), "other").unwrap(); // 同じサイズで別の内容
+    fs::write(root.join("unique"), "unique size").unwrap();
+    let finder = Finder::new();
+    // 同じサイズの2つ目で最初のファイルと一緒にハッシュを計算する
+    let dd = |name: &str, size: u64| DD {
+        input: iomod::path_to_string(root.join(name)),
+        output: iomod::path_to_string(root.join(name)),
+        size,
+        src_dev: 0,
+        dst_dev: 0,
+        action: files::DO,
+        reason: "hash",
+        cmr_mode: files::_HASH,
+        algorithm: files::_STD,
+    };
+    assert!(finder.seen(dd("unique", 11)).is_empty());
+    assert!(finder.seen(dd("a/1.flac", 5)).is_empty());
+    let requests: Vec<DD> = ["a/2.flac", "3.flac", "other"]
+        .into_iter()
+        .flat_map(|x| finder.seen(dd(x, 5)))
+        .collect();
+    assert_eq!(4, requests.len());
+    for request in &requests {
+        let hash: Hash = blake3::hash(&fs::read(&request.input).unwrap());
+        finder.record(&request.input, 5, hash);
+    }
+    let groups: Vec<Group> = finder.groups();
+    assert_eq!(1, groups.len());
+    assert_eq!(
+        vec![
+            root.join("3.flac"),
+            root.join("a/1.flac"),
+            root.join("a/2.flac")
+        ],
+        groups[0].paths
+    );
+    assert_eq!(10, groups[0].wasted);
+
+    // ハッシュの計算後に変更されたファイルは置き換えない
+    fs::write(root.join("a/2.flac"), "ALBUM").unwrap();
+    let (keep, group) = (&groups[0].paths[0], &groups[0]);
+    assert!(replace_with_link(keep, &group.paths[2], group).is_err());
+    assert_eq!("ALBUM", fs::read_to_string(root.join("a/2.flac")).unwrap());
+    fs::write(root.join("a/2.flac"), "album").unwrap();
+
+    // ハードリンクに置き換えると無駄な容量はなくなる
+    for duplicate in &group.paths[1..] {
+        assert!(replace_with_link(keep, duplicate, group).unwrap());
+    }
+    assert_eq!("album", fs::read_to_string(root.join("a/2.flac")).unwrap());
+    #[cfg(unix)]
+    assert!(finder.groups().is_empty());
+}
@@ -0,0 +1,76 @@
+/**
+ * Copyright (C) 2023 awk4j - https://ja.osdn.net/projects/awk4j/
+ * <p>
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ * <p>
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ * <p>
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+use chrono::Local;
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{BufWriter, Result, Write};
+use std::sync::Mutex;
+
+/**
+ * Events - 機械可読なイベント (JSON Lines) の出力 (--json, --json=<file>)
+ *
+ * 1イベント1行の JSON を stdout またはファイルに書き出す
+ * job_started, file_queued, file_started, file_completed, file_failed, job_finished
+ */
+pub struct Events {
+    out: Mutex<Option<Box<dyn Write + Send>>>, // None: 無効
+    stdout: bool,                              // stdout に出力する
+}
+
+impl Events {
+    // 無効 (イベントを出力しない)
+    pub fn none() -> Events {
+        Events {
+            out: Mutex::new(None),
+            stdout: false,
+        }
+    }
+
+    // path が空なら stdout に出力する
+    pub fn open(path: &str) -> Result<Events> {
+        let out: Box<dyn Write + Send> = if path.is_empty() {
+            Box::new(std::io::stdout())
+        } else {
+            Box::new(BufWriter::new(File::create(path)?))
+        };
+        Ok(Events {
+            out: Mutex::new(Some(out)),
+            stdout: path.is_empty(),
+        })
+    }
+
+    // stdout が JSON 専用になる (テキストの表示をしない)
+    pub fn is_stdout(&self) -> bool {
+        self.stdout
+    }
+
+    // イベント名と時刻を付けて1行で書き出す
+    pub fn emit(&self, event: &str, fields: Value) {
+        let mut out = self.out.lock().unwrap();
+        if let Some(w) = out.as_mut() {
+            let mut line: Value = json!({
+                "event": event,
+                "time": Local::now().to_rfc3339(),
+            });
+            if let (Some(map), Value::Object(fields)) = (line.as_object_mut(), fields) {
+                map.extend(fields);
+            }
+            let _ = writeln!(w, "{}", line);
+            let _ = w.flush();
+        }
+    }
+}
@@ -0,0 +1,52 @@
+/**
+ * Copyright (C) 2023 awk4j - https://ja.osdn.net/projects/awk4j/
+ * <p>
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ * <p>
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ * <p>
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+use regex::Regex;
+
+use crate::archive::Extractor;
+use crate::dedupe::Dedupe;
+use crate::dupes::Finder;
+use crate::manifest::Manifest;
+use crate::snapshot::Snapshot;
+use crate::trash::Trash;
+use crate::versions::Versions;
+
+/**
+ * Features - ジョブのオプション機能 (--exclude, --trash, --dedupe など)
+ *
+ * Scheduler はキューとカウンターだけを持ち、
+ * 機能ごとの状態はここにまとめて Arc<Features> を walker と worker に渡す
+ * ジョブの開始前に main が組み立て、実行中は変更しない
+ */
+#[derive(Default)]
+pub struct Features {
+    pub filters: Vec<Regex>,          // 除外するパス (--exclude)
+    pub verify: bool,                 // コピー後に出力を読み直す (--verify)
+    pub manifest: Option<Manifest>,   // ハッシュ付きの一覧 (--manifest)
+    pub trash: Option<Trash>,         // 削除・上書きするファイルの隔離 (--trash)
+    pub versions: Option<Versions>,   // 上書きするファイルの世代 (--versions)
+    pub snapshot: Option<Snapshot>,   // 日付のフォルダへの差分バックアップ (--snapshot)
+    pub dedupe: Option<Dedupe>,       // 出力フォルダの重複排除 (--dedupe)
+    pub extractor: Option<Extractor>, // 入力のアーカイブ (tar, zip)
+    pub finder: Option<Finder>,       // 重複ファイルの検出 (dupes)
+}
+
+impl Features {
+    // 除外するファイル (unix 形式のパスに正規表現を適用する)
+    pub fn is_excluded(&self, path: &str) -> bool {
+        self.filters.iter().any(|x| x.is_match(path))
+    }
+}
@@ -14,84 +14,381 @@
  * You should have received a copy of the GNU General Public License
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
+use async_std::channel::{Receiver, Sender};
 use std::fs;
 use std::io;
-use std::path::Path;
-// use std::path::{Path, PathBuf};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicI32;
+use std::sync::Arc;
+use std::time::SystemTime;
 
+use crate::archive;
+use crate::archive::Extractor;
+use crate::atomic;
+use crate::features::Features;
 use crate::iomod;
-use crate::thmod;
+use crate::messages::{tr, trf};
+use crate::thmod::Scheduler;
 
-pub fn search_fils(input: &str, output: &str, ee: EE) -> io::Result<()> {
-    let ipath: &Path = Path::new(input);
-    let opath: &Path = Path::new(output);
-    visit_dir(ipath, opath, ee)?;
+/**
+ * 入力フォルダを走査してリクエストを投げる
+ *
+ * worker と並行して実行される非同期タスク
+ * WALKERS 個の walker がフォルダの一覧から取り出して並列に走査する（ネットワークドライブ向け）
+ * 見つけたサブフォルダは一覧に戻すので、同時に走査するフォルダは WALKERS 個まで
+ */
+pub async fn search_fils(
+    input: &str,
+    output: &str,
+    ee: EE,
+    sched: Arc<Scheduler>,
+    features: Arc<Features>,
+) -> io::Result<()> {
+    let (folders, next) = async_std::channel::unbounded::<(PathBuf, PathBuf)>();
+    let pending: AtomicI32 = AtomicI32::new(1); // 一覧に入っているか走査中のフォルダ
+    let _ = folders.try_send((PathBuf::from(input), PathBuf::from(output)));
+    let walkers = (0..WALKERS).map(|_| walk(&folders, &next, &pending, ee, &sched, &features));
+    // すべてのフォルダの完了を待つ (EOF の後に put しないように)
+    futures::future::join_all(walkers).await;
     Ok(())
 }
 
-fn visit_dir<P: AsRef<Path>>(path: P, opath: &Path, ee: EE) -> io::Result<()> {
+// walker - 一覧が閉じるまでフォルダを取り出して走査する
+async fn walk(
+    folders: &Sender<(PathBuf, PathBuf)>,
+    next: &Receiver<(PathBuf, PathBuf)>,
+    pending: &AtomicI32,
+    ee: EE,
+    sched: &Arc<Scheduler>,
+    features: &Arc<Features>,
+) {
+    use async_std::task;
+    while let Ok((path, opath)) = next.recv().await {
+        // read_dir, metadata はブロックするので専用スレッドで実行する
+        // リクエストはここで put する (専用スレッドをキューの空き待ちで止めない)
+        let (_path, _sched, _features) = (path.clone(), sched.clone(), features.clone());
+        let scanned: io::Result<Scanned> =
+            task::spawn_blocking(move || scan_dir(&_path, &opath, ee, &_sched, &_features)).await;
+        match scanned {
+            Ok((subdirs, requests)) => {
+                for subdir in subdirs {
+                    atomic::atomic_add(pending, 1);
+                    let _ = folders.try_send(subdir); // 他の walker が先に走査を始める
+                }
+                for dd in requests {
+                    sched.put(dd).await;
+                }
+            }
+            Err(e) => sched.error(format!("{}: {:?} {}", iomod::red(tr("search")), path, e)),
+        }
+        if atomic::atomic_add(pending, -1) == 0 {
+            folders.close(); // すべてのフォルダを走査した
+        }
+    }
+}
+
+// フォルダ直下を調べる -> (サブフォルダの一覧, ファイルのリクエスト)
+fn scan_dir(
+    path: &Path,
+    opath: &Path,
+    ee: EE,
+    sched: &Scheduler,
+    features: &Features,
+) -> io::Result<Scanned> {
+    let mut subdirs: Vec<(PathBuf, PathBuf)> = Vec::new();
+    let mut requests: Vec<DD> = Vec::new();
+    // デバイスはフォルダごとに1回だけ調べる (ネットワークドライブでは重い)
+    let devices: (u64, u64) = (iomod::device_id(path)?, iomod::device_id(opath)?);
     for entry in fs::read_dir(path)? {
         let entry = entry?;
-        let _name: String = iomod::get_filename(&entry.path());
-        let _opath: &Path = &opath.join(_name); // output file
-        if entry.file_type()?.is_dir() {
-            // println!("+{:?}\t{:?}", entry.path(), _opath);
-            iomod::mkdir(_opath); // Create deep folder
-            visit_dir(entry.path(), _opath, ee.clone())?;
+        let _name: String = iomod::get_filename(entry.path());
+        let _opath: PathBuf = opath.join(_name); // output file
+        let file_type: fs::FileType = entry.file_type()?;
+        if file_type.is_dir() {
+            log::debug!("+{:?}\t{:?}", entry.path(), _opath);
+            if let Err(e) = mkdir(&_opath) {
+                // 同じ名前のファイルがあるなど、フォルダごとスキップする
+                sched.error(format!(
+                    "{}: {:?} {}",
+                    iomod::red(tr("search")),
+                    entry.path(),
+                    e
+                ));
+                continue;
+            }
+            subdirs.push((entry.path(), _opath));
+        } else if features.is_excluded(&iomod::path_to_unix(entry.path())) {
+            log::debug!("exclude: {:?}", entry.path()); // --exclude
+        } else if ee.cmr_mode == _HASH && !file_type.is_file() {
+            log::debug!("dupes: {:?}", entry.path()); // シンボリックリンクなどは数えない
         } else {
-            // println!(".{:?}\t{:?}", entry.path(), _opath);
-            make_dd(&entry.path(), _opath, ee.clone());
+            log::trace!(".{:?}\t{:?}", entry.path(), _opath);
+            match make_dd(&entry.path(), &_opath, devices, ee, features) {
+                Ok(Some(dd)) => match &features.finder {
+                    Some(finder) => requests.extend(finder.seen(dd)), // dupes
+                    None => requests.push(dd),
+                },
+                Ok(None) => (),
+                Err(e) => sched.error(format!("{:?} {}", entry.path(), e)), // ファイルごとのエラー
+            }
+        }
+    }
+    Ok((subdirs, requests))
+}
+
+// 出力フォルダを作る (既存のフォルダはそのまま)
+fn mkdir(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        return Ok(());
+    }
+    fs::create_dir_all(path)
+}
+
+// 走査の結果 (サブフォルダの入力と出力, ファイルのリクエスト)
+type Scanned = (Vec<(PathBuf, PathBuf)>, Vec<DD>);
+
+/**
+ * アーカイブのエントリーをリクエストする (copy <ARCHIVE> <DST>)
+ *
+ * フォルダはここで作り、ファイルは worker が取り出す
+ * シンボリックリンクはすべてのファイルの後で作る (archive_symlinks)
+ * 出力フォルダの中のシンボリックリンクを通るエントリーは書き込まない (tar-slip)
+ * read_dir の代わりにエントリーの一覧を使い、MEMBERS 個ずつ専用スレッドで調べて put する
+ * 取り出せないエントリーとフォルダを作れないエントリーは失敗として数える
+ */
+pub async fn search_archive(
+    output: &str,
+    ee: EE,
+    sched: Arc<Scheduler>,
+    features: Arc<Features>,
+) -> io::Result<()> {
+    use async_std::task;
+    if let Some(extractor) = &features.extractor {
+        for (input, e) in extractor.skipped() {
+            sched.failed_entry(input, output, "copy", e); // ハードリンク, 外を指す名前など
+        }
+    }
+    let count: usize = features.extractor.as_ref().map_or(0, |x| x.members().len());
+    for start in (0..count).step_by(MEMBERS) {
+        let range: Range<usize> = start..(start + MEMBERS).min(count);
+        let (_output, _sched, _features) = (output.to_string(), sched.clone(), features.clone());
+        let requests: Vec<DD> =
+            task::spawn_blocking(move || scan_members(&_output, range, ee, &_sched, &_features))
+                .await?;
+        for dd in requests {
+            sched.put(dd).await;
         }
     }
     Ok(())
 }
 
+// アーカイブのエントリーの一部を調べる -> ファイルのリクエスト
+fn scan_members(
+    output: &str,
+    range: Range<usize>,
+    ee: EE,
+    sched: &Scheduler,
+    features: &Features,
+) -> io::Result<Vec<DD>> {
+    let Some(extractor) = &features.extractor else {
+        return Ok(Vec::new());
+    };
+    let opath: &Path = Path::new(output);
+    let devices: (u64, u64) = (
+        iomod::device_id(extractor.path())?,
+        iomod::device_id(opath)?,
+    );
+    let mut requests: Vec<DD> = Vec::new();
+    for member in &extractor.members()[range] {
+        let _opath: PathBuf = opath.join(&member.name);
+        let input: String = extractor.input(member);
+        if member.kind == archive::_SYMLINK {
+            continue; // 後で作る
+        } else if let Some(link) = archive::through_symlink(opath, &member.name) {
+            let e = symlink_in_path(&link);
+            sched.failed_entry(&input, &iomod::path_to_string(&_opath), "copy", &e);
+        } else if member.kind == archive::_DIR {
+            if let Err(e) = mkdir(&_opath) {
+                sched.failed_entry(&input, &iomod::path_to_string(&_opath), "copy", &e);
+            }
+        } else if features.is_excluded(&iomod::path_to_unix(&input)) {
+            log::debug!("exclude: {}", input); // --exclude
+        } else {
+            // フォルダのエントリーが無いアーカイブもある
+            if let Err(e) = _opath.parent().map_or(Ok(()), mkdir) {
+                sched.failed_entry(&input, &iomod::path_to_string(&_opath), "copy", &e);
+                continue;
+            }
+            requests.push(make_member_dd(
+                extractor, member, &_opath, devices, ee, features,
+            ));
+        }
+    }
+    Ok(requests)
+}
+
+/**
+ * アーカイブのシンボリックリンクを作る (すべてのファイルを取り出した後)
+ *
+ * 先に作ると、後のエントリーがリンクを通って出力フォルダの外に書き込める
+ */
+pub fn archive_symlinks(output: &str, sched: &Scheduler, features: &Features) {
+    let Some(extractor) = &features.extractor else {
+        return;
+    };
+    let opath: &Path = Path::new(output);
+    for member in extractor.members() {
+        let _opath: PathBuf = opath.join(&member.name);
+        let input: String = extractor.input(member);
+        let parent: &str = member.name.rsplit_once('/').map_or("", |x| x.0);
+        if member.kind != archive::_SYMLINK || features.is_excluded(&iomod::path_to_unix(&input)) {
+            continue;
+        }
+        let created: io::Result<()> = match archive::through_symlink(opath, parent) {
+            Some(link) => Err(symlink_in_path(&link)),
+            None => _opath
+                .parent()
+                .map_or(Ok(()), mkdir)
+                .and_then(|_| extractor.symlink(member, &_opath)),
+        };
+        if let Err(e) = created {
+            sched.failed_entry(&input, &iomod::path_to_string(&_opath), "copy", &e);
+        }
+    }
+}
+
+// 出力パスの途中のシンボリックリンクを通るエントリー (tar-slip)
+fn symlink_in_path(link: &Path) -> io::Error {
+    let message: String = trf("symlink_in_path", &[&format!("{:?}", link)]);
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+// アーカイブのエントリーのリクエスト (judgment はエントリーの長さと日時で行う)
+fn make_member_dd(
+    extractor: &Extractor,
+    member: &archive::Member,
+    _output: &Path,
+    (src_dev, dst_dev): (u64, u64),
+    ee: EE,
+    features: &Features,
+) -> DD {
+    let input: String = extractor.input(member);
+    let snapshot = features.snapshot.as_ref();
+    let (action, reason) = match snapshot.and_then(|x| x.link_source(_output)) {
+        Some(previous) => match judgment(member.size, member.mtime, &previous) {
+            (SKIP, reason) => (LINK, reason),
+            x => x,
+        },
+        None => judgment(member.size, member.mtime, _output),
+    };
+    DD {
+        src_dev,                                // archive device
+        dst_dev,                                // output device
+        input,                                  // archive/entry
+        output: iomod::path_to_string(_output), // output file
+        size: member.size,                      // entry length
+        action,                                 // DO, SKIP, LINK
+        reason,                                 // 判定の理由
+        cmr_mode: ee.cmr_mode,                  // copy
+        algorithm: ee.algorithm,                // Buffer number
+    }
+}
+
 // https://runebook.dev/ja/docs/rust/std/fs/struct.metadata
-// リクエスト(構造体)を作成し投げる
-fn make_dd(_input: &Path, _output: &Path, ee: EE) {
+// リクエスト(構造体)を作成する (None: リクエストしない)
+fn make_dd(
+    _input: &Path,
+    _output: &Path,
+    (src_dev, dst_dev): (u64, u64),
+    ee: EE,
+    features: &Features,
+) -> io::Result<Option<DD>> {
     let input: String = iomod::path_to_string(_input);
     let output: String = iomod::path_to_string(_output);
-    let action: i8 = judgment(_input, _output);
+    let metadata: fs::Metadata = fs::metadata(_input)?; // リンク切れなど
+    let size: u64 = metadata.len();
+    let itime: SystemTime = metadata.modified()?;
+    // スナップショットは前回のファイルと比べ、変わっていなければリンクする
+    let snapshot = features.snapshot.as_ref();
+    let (action, reason) = match snapshot.and_then(|x| x.link_source(_output)) {
+        _ if ee.cmr_mode == _HASH => (DO, "hash"),
+        Some(previous) => match judgment(size, itime, &previous) {
+            (SKIP, reason) => (LINK, reason),
+            x => x,
+        },
+        None => judgment(size, itime, _output),
+    };
     let dd = DD {
         input,                   // input file
         output,                  // output file
-        action,                  // DO, SKIP
-        cmr_mode: ee.cmr_mode,   // copy, move, rename
+        size,                    // input file length
+        src_dev,                 // input device
+        dst_dev,                 // output device
+        action,                  // DO, SKIP, LINK
+        reason,                  // 判定の理由
+        cmr_mode: ee.cmr_mode,   // copy, move, rename, hash
         algorithm: ee.algorithm, // Buffer number
     };
-    thmod::put(dd);
+    Ok(Some(dd))
 }
 
-// コピーするかどうかを決定する
-fn judgment(input: &Path, output: &Path) -> i8 {
-    if !output.is_file() {
-        return DO; // 出力ファイルが存在しない
-    }
-    let ilen = iomod::get_meta_len(input);
-    let olen = iomod::get_meta_len(output);
-    if ilen != olen {
-        return DO; // 長さが異なる
+// コピーするかどうかを入力の長さと日時で決定する -> (DO/SKIP, 理由)
+fn judgment(ilen: u64, itime: SystemTime, output: &Path) -> (i8, &'static str) {
+    let metadata: fs::Metadata = match fs::metadata(output) {
+        Ok(x) if x.is_file() => x,
+        _ => return (DO, "new"), // 出力ファイルが存在しない
+    };
+    if ilen != metadata.len() {
+        return (DO, "size differs"); // 長さが異なる
     }
-    let itime = iomod::get_meta_modified(input);
-    let otime = iomod::get_meta_modified(output);
+    let Ok(otime) = metadata.modified() else {
+        return (DO, "new");
+    };
     if let Ok(epoch) = itime.duration_since(otime) {
         if epoch.as_secs() == 0 && epoch.as_millis() == 0 {
-            // println!("same = {}.{:03}", epoch.as_secs(), epoch.as_millis());
-            return SKIP; // 日時が等しい
+            log::trace!("same = {}.{:03}", epoch.as_secs(), epoch.as_millis());
+            return (SKIP, "same time"); // 日時が等しい
         }
     } else {
-        return SKIP; // 出力側の日時が新しい
+        return (SKIP, "output newer"); // 出力側の日時が新しい
     }
-    DO
+    (DO, "input newer")
 }
 
+// イベント・ログ用のアクション名
+pub fn action_name(dd: &DD) -> &'static str {
+    if dd.action == SKIP {
+        "skip"
+    } else if dd.action == LINK {
+        "link"
+    } else if dd.cmr_mode == _HASH {
+        "hash"
+    } else if dd.cmr_mode == _MOVE {
+        "move"
+    } else if dd.cmr_mode == _RENAME {
+        "rename"
+    } else {
+        "copy"
+    }
+}
+
+// 同時に走査するフォルダの数 (walker の数)
+const WALKERS: usize = 8;
+// アーカイブのエントリーを調べる単位 (専用スレッドごと)
+const MEMBERS: usize = 256;
+
 // Action - execution mode (cmr)
 pub const _COPY: char = 'c';
 pub const _MOVE: char = 'm';
 pub const _RENAME: char = 'r';
+// dupes (入力のハッシュを計算するだけ)
+pub const _HASH: char = 'h';
 // Action - Possibility of execution
 pub const DO: i8 = 1;
 pub const SKIP: i8 = 2;
+// 前回のスナップショットからハードリンク
+pub const LINK: i8 = 3;
 // Algorithm
 pub const _STD: u8 = 0;
 pub const _MAXBUF: u8 = 1;
@@ -101,11 +398,15 @@ pub const _TEST: u8 = 9;
 // Daemon descriptor - 構造体、クローン可能
 #[derive(Debug, Clone)] // String は Copy を実装できない
 pub struct DD {
-    pub input: String,  // input file
-    pub output: String, // output file
-    pub action: i8,     // DO, SKIP
-    pub cmr_mode: char, // copy, move, rename
-    pub algorithm: u8,  // Algorithm
+    pub input: String,        // input file
+    pub output: String,       // output file
+    pub size: u64,            // input file length
+    pub src_dev: u64,         // input device
+    pub dst_dev: u64,         // output device
+    pub action: i8,           // DO, SKIP, LINK
+    pub reason: &'static str, // 判定の理由
+    pub cmr_mode: char,       // copy, move, rename, hash
+    pub algorithm: u8,        // Algorithm
 }
 impl DD {
     // pub fn _get_input(&self) -> String {
@@ -117,3 +418,39 @@ pub struct EE {
     pub cmr_mode: char, // copy, move, rename
     pub algorithm: u8,  // Algorithm
 }
+
+#[cfg(test)]
+#[test]
+fn walk_test() {
+    use crate::daemon;
+    use crate::thmod;
+    // フォルダが多く、キャパシティが小さくても走査が止まらない
+    let root = iomod::TempDir::new("walk_test");
+    let (input, output) = (root.join("in"), root.join("out"));
+    for n in 0..1200 {
+        let dir: PathBuf = input.join(format!("d{}", n));
+        fs::create_dir_all(&dir).unwrap();
+        for m in 0..6 {
+            fs::write(dir.join(format!("{}.txt", m)), "x").unwrap();
+        }
+    }
+    fs::create_dir_all(&output).unwrap();
+    fs::write(output.join("d0"), "file").unwrap(); // 同じ名前のファイルはエラーにする
+    let mut sched = Scheduler::new(thmod::_FIFO, 2, [2, 0]);
+    sched.set_quiet(true);
+    let sched = Arc::new(sched);
+    let ee = EE {
+        cmr_mode: _COPY,
+        algorithm: _STD,
+    };
+    daemon::main(
+        &sched,
+        &Arc::new(Features::default()),
+        &iomod::path_to_string(&input),
+        &iomod::path_to_string(&output),
+        ee,
+    );
+    assert_eq!((7194, 0), (sched.totals().0, sched.totals().1));
+    assert!(output.join("d1199/5.txt").is_file());
+    assert_eq!(1, sched.errors().len());
+}
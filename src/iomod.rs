@@ -19,13 +19,15 @@ use std::fs::canonicalize;
 use std::fs::File;
 use std::fs::Metadata;
 use std::io::prelude::*;
+use std::io::IsTerminal;
 use std::io::Result;
 use std::io::{BufReader, BufWriter};
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 use std::time::SystemTime;
 
-/**
+/*
 // Path::is_file()
 // Path::is_dir()
 
@@ -43,11 +45,10 @@ use std::time::SystemTime;
  */
 pub fn absolute_path<P: AsRef<Path>>(path: P) -> String {
     let p: &Path = path.as_ref();
-    let abs_path = match canonicalize(p) {
+    match canonicalize(p) {
         Err(e) => panic!("{}", error("absolute", p, e.to_string())),
         Ok(x) => path_to_string(&x),
-    };
-    abs_path
+    }
 }
 
 /**
@@ -67,45 +68,64 @@ pub fn path_to_unix<P: AsRef<Path>>(path: P) -> String {
 /**
  * mkdir - 深い階層のディレクトリを一気に作成
  */
-pub fn mkdir<P: AsRef<Path>>(path: P) -> () {
+pub fn mkdir<P: AsRef<Path>>(path: P) {
     let p: &Path = path.as_ref();
     if !p.is_dir() {
-        match fs::create_dir_all(p) {
-            // 既に存在するファイルを作成することはできません
-            // 指定されたパスが見つかりません - Z:/foo
-            Err(e) => panic!("{}", error("mkdir", p, e.to_string())),
-            Ok(_) => (),
+        // 既に存在するファイルを作成することはできません
+        // 指定されたパスが見つかりません - Z:/foo
+        if let Err(e) = fs::create_dir_all(p) {
+            panic!("{}", error("mkdir", p, e.to_string()));
+        }
+    }
+}
+
+/**
+ * remove empty directories - 空になったサブフォルダを下から順に削除 -> 削除した数
+ *
+ * move の後始末に使う (ファイルが残っているフォルダと root 自身は削除しない)
+ */
+pub fn remove_empty_dirs<P: AsRef<Path>>(root: P) -> usize {
+    fn visit(dir: &Path) -> usize {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return 0;
+        };
+        let mut removed: usize = 0;
+        for entry in entries.flatten() {
+            let path: PathBuf = entry.path();
+            if entry.file_type().map(|x| x.is_dir()).unwrap_or(false) {
+                removed += visit(&path);
+                if fs::remove_dir(&path).is_ok() {
+                    removed += 1; // 空のときだけ削除できる
+                }
+            }
         }
+        removed
     }
-    ()
+    visit(root.as_ref())
 }
 
 /**
  * remove directory - フォルダをファイルを含めてまるごと削除
  */
-pub fn remove_dir_all<P: AsRef<Path>>(path: P) -> () {
+pub fn _remove_dir_all<P: AsRef<Path>>(path: P) {
     let p: &Path = path.as_ref();
     if p.is_dir() {
-        match fs::remove_dir_all(p) {
-            Err(e) => panic!("{}", error("rmdir", p, e.to_string())),
-            Ok(_) => (),
+        if let Err(e) = fs::remove_dir_all(p) {
+            panic!("{}", error("rmdir", p, e.to_string()));
         }
     }
-    ()
 }
 
 /**
  * remove file
  */
-pub fn _remove_file<P: AsRef<Path>>(path: P) -> () {
+pub fn _remove_file<P: AsRef<Path>>(path: P) {
     let p: &Path = path.as_ref();
     if p.is_file() {
-        match fs::remove_file(p) {
-            Err(e) => panic!("{}", error("rmfile", p, e.to_string())),
-            Ok(_) => (),
+        if let Err(e) = fs::remove_file(p) {
+            panic!("{}", error("rmfile", p, e.to_string()));
         }
     }
-    ()
 }
 
 /**
@@ -142,12 +162,12 @@ pub fn _copy<P: AsRef<Path>>(from: P, to: P) -> u64 {
 /// to が親ディレクトリを持つ場合、そのディレクトリを先に作成する必要が有ります。
 /// to が別のマウントポイント(または別ドライブ)にある場合、機能しません。
 /// https://runebook.dev/ja/docs/rust/std/fs/fn.rename
-pub fn _rename_file<P: AsRef<Path>>(from: P, to: P) -> () {
+pub fn _rename_file<P: AsRef<Path>>(from: P, to: P) {
     let f: &Path = from.as_ref();
     let t: &Path = to.as_ref();
-    match fs::rename(f, t) {
-        Err(e) => panic!("{}", error("rename", f, e.to_string())),
-        Ok(_) => (), // ファイルを別のディスク ドライブに移動できません
+    // ファイルを別のディスク ドライブに移動できません
+    if let Err(e) = fs::rename(f, t) {
+        panic!("{}", error("rename", f, e.to_string()));
     }
 }
 
@@ -155,22 +175,59 @@ pub fn _rename_file<P: AsRef<Path>>(from: P, to: P) -> () {
 /**
  * get metadata - length    
  */
-pub fn get_meta_len<P: AsRef<Path>>(path: P) -> u64 {
+pub fn _get_meta_len<P: AsRef<Path>>(path: P) -> u64 {
     let p: &Path = path.as_ref();
     let metadata: Result<Metadata> = fs::metadata(p);
     metadata.expect("REASON").len()
 }
 // get metadata - modified
-pub fn get_meta_modified<P: AsRef<Path>>(path: P) -> SystemTime {
+pub fn _get_meta_modified<P: AsRef<Path>>(path: P) -> SystemTime {
     let p: &Path = path.as_ref();
     let metadata: Result<Metadata> = fs::metadata(p);
     if let Ok(time) = metadata.expect("REASON").modified() {
-        return time;
+        time
     } else {
         panic!("Not supported on this platform");
     }
 }
 
+/**
+ * get device id - デバイスごとの同時実行数の制限に使う
+ *
+ * UNIX はメタデータの st_dev、Windows はドライブ名 (UNC の prefix) から作る
+ */
+#[cfg(unix)]
+pub fn device_id<P: AsRef<Path>>(path: P) -> Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(fs::metadata(path.as_ref())?.dev())
+}
+#[cfg(not(unix))]
+pub fn device_id<P: AsRef<Path>>(path: P) -> Result<u64> {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    let abs: PathBuf = canonicalize(path.as_ref())?; // \\?\D:\foo
+    let mut hasher = DefaultHasher::new();
+    abs.components().next().hash(&mut hasher);
+    Ok(hasher.finish())
+}
+
+/**
+ * is shared - 他のパスとハードリンクを共有しているファイルか
+ *
+ * 他のパスと共有しているファイルを truncate して書き換えないように使う
+ * unix 以外はリンクの数を調べられないので、既存のファイルは常に共有しているとみなす
+ * (出力ファイルを削除してから書き込む)
+ */
+#[cfg(unix)]
+pub fn is_shared<P: AsRef<Path>>(path: P) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    fs::symlink_metadata(path.as_ref()).is_ok_and(|x| x.is_file() && x.nlink() > 1)
+}
+#[cfg(not(unix))]
+pub fn is_shared<P: AsRef<Path>>(path: P) -> bool {
+    fs::symlink_metadata(path.as_ref()).is_ok_and(|x| x.is_file())
+}
+
 /**
  * change directory - dir, foo/var --> dir/var
  */
@@ -238,8 +295,8 @@ pub fn _open_writer(path: &str) -> (FD, BufWriter<File>) {
 }
 // Write line
 pub fn _write(mut fd: FD, mut writer: BufWriter<File>) -> (FD, BufWriter<File>) {
-    let rs: Result<_> = write!(writer, "{}\n", fd._get_buf());
-    let _rs = match rs {
+    let rs: Result<_> = writeln!(writer, "{}", fd._get_buf());
+    match rs {
         Err(e) => panic!("{}", error("Write Error", &fd.path, e.to_string())),
         Ok(x) => x,
     };
@@ -248,6 +305,7 @@ pub fn _write(mut fd: FD, mut writer: BufWriter<File>) -> (FD, BufWriter<File>)
 }
 
 // File descriptor (Read/Write) - 構造体、クローン可能
+#[allow(dead_code)] // Test Reader/Writer 専用
 #[derive(Debug, Clone)] // String は Copy を実装できない
 pub struct FD {
     pub path: String,     // used in error messages
@@ -303,6 +361,53 @@ fn path_test() {
     println!("ok, Path-->String: {}", sp);
 }
 
+/**
+ * テスト用の一時フォルダ - テストが panic しても drop で削除する
+ */
+#[cfg(test)]
+pub struct TempDir(PathBuf);
+#[cfg(test)]
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let root: PathBuf = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root); // 前回の残り
+        fs::create_dir_all(&root).unwrap();
+        TempDir(root)
+    }
+}
+#[cfg(test)]
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+#[cfg(test)]
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn remove_empty_dirs_test() {
+    let root = TempDir::new("rmdirs_test");
+    fs::create_dir_all(root.join("a/b/c")).unwrap();
+    fs::create_dir_all(root.join("d/e")).unwrap();
+    fs::write(root.join("d/failed.txt"), b"x").unwrap(); // 残っているファイル
+    assert_eq!(4, remove_empty_dirs(&root)); // a/b/c, a/b, a, d/e
+    assert!(root.is_dir()); // root は残す
+    assert!(root.join("d/failed.txt").is_file());
+    assert!(!root.join("a").exists());
+}
+
 const _TEST: &str = "../foo/var";
 const _IN: &str = "SAMPLE.html";
 const _OUT: &str = "~SAMPLE.html";
@@ -325,18 +430,31 @@ const BLUE: &str = "\x1b[94m"; // options
 const _MAGENTA: &str = "\x1b[95m"; // warning
 const CYAN: &str = "\x1b[96m"; // information
 
+// 色を付けるか (stdout が端末で NO_COLOR が無いとき, 最初の表示で1回だけ決める)
+// --json, tar の stdout などのパイプには色を付けない
+static COLOR: OnceLock<bool> = OnceLock::new();
+
+fn color<T: std::fmt::Display>(code: &str, msg: T) -> String {
+    let color: bool = *COLOR
+        .get_or_init(|| std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none());
+    if !color {
+        return msg.to_string();
+    }
+    format!("{}{}{}", code, msg, RESET)
+}
+
 // オーバーロード機能は無いですか? → ジェネリックスを使えば良さげです。
 // To RED → msg: は &str or String を受入れる
 pub fn red<T: std::fmt::Display>(msg: T) -> String {
-    format!("{}{}{}", RED, msg, RESET)
+    color(RED, msg)
 }
 // To BLUE
 pub fn blue<T: std::fmt::Display>(msg: T) -> String {
-    format!("{}{}{}", BLUE, msg, RESET)
+    color(BLUE, msg)
 }
 // To Cyan
 pub fn cyan<T: std::fmt::Display>(msg: T) -> String {
-    format!("{}{}{}", CYAN, msg, RESET)
+    color(CYAN, msg)
 }
 
 // Error message → file: は &str or &Path を受入れる
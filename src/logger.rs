@@ -0,0 +1,159 @@
+/**
+ * Copyright (C) 2023 awk4j - https://ja.osdn.net/projects/awk4j/
+ * <p>
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ * <p>
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ * <p>
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+use chrono::Local;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/**
+ * Logger - レベル付きのログファイル (--log=<file>, --log-level=<level>)
+ *
+ * error/warn/info/debug/trace を日時付きでファイルに追記する
+ * MAX_SIZE を超えたら copy.log → copy.log.1 → … copy.log.5 とローテーションする
+ * log クレートのマクロ (log::info! など) から使う
+ */
+struct Logger {
+    level: LevelFilter,
+    file: Mutex<Rotating>,
+}
+
+// ローテーションするログファイル
+struct Rotating {
+    path: PathBuf, // log file
+    file: File,    // append
+    size: u64,     // 現在のサイズ
+    max_size: u64, // ローテーションするサイズ
+}
+
+const MAX_SIZE: u64 = 10 * 1024 * 1024; // 10 MiB
+const KEEP: usize = 5; // 残す世代数
+
+impl Rotating {
+    fn open(path: &Path, max_size: u64) -> Result<Rotating> {
+        let file: File = OpenOptions::new().create(true).append(true).open(path)?;
+        let size: u64 = file.metadata()?.len();
+        Ok(Rotating {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+        })
+    }
+
+    fn write(&mut self, line: &str) -> Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    // copy.log.4 → copy.log.5, …, copy.log → copy.log.1
+    fn rotate(&mut self) -> Result<()> {
+        for i in (1..KEEP).rev() {
+            let from: PathBuf = generation(&self.path, i);
+            if from.is_file() {
+                fs::rename(&from, generation(&self.path, i + 1))?;
+            }
+        }
+        fs::rename(&self.path, generation(&self.path, 1))?;
+        *self = Rotating::open(&self.path.clone(), self.max_size)?;
+        Ok(())
+    }
+}
+
+// copy.log → copy.log.n
+fn generation(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line: String = format!(
+            "{} {:<5} {}: {}\n",
+            Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write(&line) {
+            eprintln!("log: {:?} {}", file.path, e);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().unwrap().file.flush();
+    }
+}
+
+/**
+ * ログファイルを開いて log クレートに登録する
+ *
+ * level: error, warn, info, debug, trace (off)
+ */
+pub fn init(path: &str, level: &str) -> Result<()> {
+    let level: LevelFilter = match LevelFilter::from_str(level) {
+        Err(_) => return Err(std::io::Error::other(format!("log level: {}", level))),
+        Ok(x) => x,
+    };
+    let logger = Logger {
+        level,
+        file: Mutex::new(Rotating::open(Path::new(path), MAX_SIZE)?),
+    };
+    if log::set_logger(Box::leak(Box::new(logger))).is_ok() {
+        log::set_max_level(level);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn rotate_test() {
+    let dir: PathBuf = std::env::temp_dir().join("copy-asyncstd-rotate-test");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path: PathBuf = dir.join("copy.log");
+    let mut file = Rotating::open(&path, 10).unwrap();
+    for line in ["123456\n", "abcdef\n", "ABCDEF\n"] {
+        file.write(line).unwrap();
+    }
+    assert_eq!("ABCDEF\n", fs::read_to_string(&path).unwrap());
+    assert_eq!(
+        "abcdef\n",
+        fs::read_to_string(generation(&path, 1)).unwrap()
+    );
+    assert_eq!(
+        "123456\n",
+        fs::read_to_string(generation(&path, 2)).unwrap()
+    );
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -17,23 +17,52 @@
 #[macro_use]
 extern crate lazy_static;
 use chrono::{DateTime, Local};
-use regex::Regex;
-use std::env;
+use clap::error::ErrorKind;
+use clap::CommandFactory;
+use serde_json::json;
 use std::path::Path;
+use std::sync::Arc;
 
+use crate::archive::Extractor;
+use crate::cli::{Cli, Command, Job};
+use crate::dedupe::Dedupe;
+use crate::events::Events;
+use crate::features::Features;
 use crate::files::EE;
+use crate::manifest::Manifest;
+use crate::messages::{tr, trf};
+use crate::report::Report;
+use crate::snapshot::Snapshot;
+use crate::thmod::Scheduler;
+use crate::throttle::Throttle;
+use crate::trash::Trash;
+use crate::versions::Versions;
 
+mod archive;
 mod asyncmod;
 mod atomic;
+mod bench;
+mod cli;
+mod config;
 mod daemon;
+mod dashboard;
+mod dedupe;
+mod dupes;
+mod events;
+mod features;
 mod files;
 mod iomod;
+mod logger;
+mod manifest;
+mod messages;
+mod report;
+mod snapshot;
 mod thmod;
+mod throttle;
+mod trash;
+mod versions;
 
-const INPUT_: &str = "_IN";
-const OUTPUT_: &str = "_OUT";
-
-// const _RE_MOUNTPOINT: &str = r"^(/[^/]+)//"; // UNIX 形式
+#[cfg(not(unix))]
 const RE_DRIVE: &str = r"^//[?.]/([a-zA-Z]:)/"; // UNC 形式
 /**
  * path -> absolute_path(canonicalize) -> ドライブ名
@@ -41,7 +70,9 @@ const RE_DRIVE: &str = r"^//[?.]/([a-zA-Z]:)/"; // UNC 形式
  * Windows上では絶対パスは「\\?\D:\foo」のような「UNC path」を返す
  * このパスからドライブ名「D:」を抽出して返す
  */
+#[cfg(not(unix))]
 fn get_drive(path: &Path) -> String {
+    use regex::Regex;
     lazy_static! {
         static ref RE_DV: Regex = Regex::new(RE_DRIVE).unwrap();
     }
@@ -54,136 +85,301 @@ fn get_drive(path: &Path) -> String {
         None => String::new(), // empty
     }
 }
-
-const RE_CAPA: &str = r"^[+]\d+$"; // キャパシティ
-const RE_THREAD: &str = r"^[-]\d+$"; // スレッド数
 /**
- * Initialize - Command line parameter analysis
+ * path -> absolute_path(canonicalize) -> マウントポイント
+ *
+ * 親フォルダをたどり、デバイス (st_dev) が変わる手前をマウントポイントとする
  */
-fn initialize() -> (String, String, EE, i32) {
-    lazy_static! { // (Regex は一度だけコンパイルされる)
-        static ref RE_CA: Regex = Regex::new(RE_CAPA).unwrap();
-        static ref RE_TH: Regex = Regex::new(RE_THREAD).unwrap();
-    }
-    let args: Vec<String> = env::args().collect();
-    let len = args.len();
-    if len < 3 {
-        let message = iomod::red("入出力フォルダが省略されています");
-        eprintln!("{}: {:?}", message, args);
+#[cfg(unix)]
+fn get_drive(path: &Path) -> String {
+    let abs: String = iomod::absolute_path(path);
+    let device: Option<u64> = iomod::device_id(&abs).ok();
+    let mut mount: &Path = Path::new(&abs);
+    while let Some(parent) = mount.parent() {
+        if iomod::device_id(parent).ok() != device {
+            break;
+        }
+        mount = parent;
     }
+    iomod::path_to_unix(mount)
+}
 
-    let _input: &str = if len > 1 { &args[1] } else { INPUT_ };
-    let _output: &str = if len > 2 { &args[2] } else { OUTPUT_ };
+/**
+ * Initialize - 解析済みのオプションからジョブを準備する
+ *
+ * フォルダの検査、ヘッダーの表示、Scheduler の作成
+ */
+fn initialize(job: &Job, cmr_mode: char) -> (String, String, EE, Arc<Scheduler>, Arc<Features>) {
+    // --snapshot は DST の中の日付のフォルダに書き込む
+    if job.snapshot && cmr_mode != files::_COPY {
+        cli::fail(ErrorKind::ArgumentConflict, tr("snapshot_copy").to_string());
+    }
+    let snapshot: Option<Snapshot> = job.snapshot.then(|| Snapshot::new(&job.output));
+    let output_dir: String = match &snapshot {
+        Some(x) => x.current(),
+        None => job.output.clone(),
+    };
+    let _input: &str = &job.input;
+    let _output: &str = &output_dir;
     let __input: String = iomod::path_to_unix(_input);
     let __output: String = iomod::path_to_unix(_output);
     let input: &Path = Path::new(_input); // 入力フォルダ
     let output: &Path = Path::new(_output); // 出力フォルダ
-    iomod::mkdir(input);
-    iomod::mkdir(output);
 
-    let mut queue: &str = "-q[ueue]";
-    let mut fifo: bool = true;
-    let mut cmr_name: &str = "-c[opy]";
-    let mut cmr_mode: char = files::_COPY;
-    let mut threads: i32 = 3;
-    let mut capa: usize = 2048;
-    let mut algorithm: u8 = files::_STD;
-    let mut algoname = "std";
-    for i in 3..len {
-        let argi = &args[i];
-        if argi.starts_with("-") {
-            if argi == "-c" {
-                cmr_name = "-c[opy]";
-                cmr_mode = files::_COPY;
-            } else if argi == "-m" {
-                cmr_name = "-m[ove]";
-                cmr_mode = files::_MOVE;
-            } else if argi == "-r" {
-                cmr_name = "-r[ename]";
-                cmr_mode = files::_RENAME;
-            } else if argi == "-q" {
-                queue = "-q[ueue]";
-                fifo = true;
-            } else if argi == "-s" {
-                queue = "-s[tack]";
-                fifo = false;
-            } else if RE_TH.is_match(argi) {
-                let tmp: i32 = argi.parse().unwrap();
-                if tmp != 0 {
-                    threads = tmp.abs();
-                }
-            } else {
-                let message = iomod::red("オプションエラー");
-                eprintln!("{}: {:?}", message, argi);
+    // tar, zip ファイルはエントリーを取り出す (copy のみ)
+    let extractor: Option<Extractor> = if input.is_file() {
+        if cmr_mode != files::_COPY {
+            cli::fail(ErrorKind::ArgumentConflict, tr("archive_copy").to_string());
+        }
+        if job.dedupe.is_some() {
+            // 取り出したファイルは既存の出力とまとめない
+            cli::fail(
+                ErrorKind::ArgumentConflict,
+                tr("archive_dedupe").to_string(),
+            );
+        }
+        match Extractor::open(input) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                let message = format!("{}: {:?} {}", tr("archive"), __input, e);
+                cli::fail(ErrorKind::ValueValidation, message);
             }
-        } else if RE_CA.is_match(argi) {
-            capa = argi.parse().unwrap();
-        } else if argi.starts_with("m") {
-            algorithm = files::_MAXBUF;
-            algoname = "maxbuf";
-        } else if argi.starts_with("c") {
-            algorithm = files::_CHANNEL;
-            algoname = "channel";
-        } else if argi.starts_with("t") {
-            algorithm = files::_TEST;
-            algoname = "test";
-        }
-    }
-    if !input.is_dir() {
-        let message = iomod::red("フォルダではありません".to_string());
-        panic!("{}: {:?}", message, __input);
-    }
+        }
+    } else if !input.is_dir() {
+        let message = trf("not_folder", &[&format!("{:?}", __input)]);
+        cli::fail(ErrorKind::ValueValidation, message);
+    } else {
+        None
+    };
+    iomod::mkdir(output);
     if !output.is_dir() {
-        let message = iomod::red("フォルダではありません".to_string());
-        panic!("{}: {:?}", message, __output);
+        let message = trf("not_folder", &[&format!("{:?}", __output)]);
+        cli::fail(ErrorKind::ValueValidation, message);
     }
+
+    let cmr_name: &str = match cmr_mode {
+        files::_MOVE => "move",
+        files::_RENAME => "rename",
+        _ => "copy",
+    };
+    let queue: String = cli::value_name(&job.queue);
+    let algoname: String = cli::value_name(&job.algorithm);
+    let threads: [i32; 2] = job.threads; // SMALL, LARGE
+    let capa: usize = job.capacity;
+    let filters: Vec<&str> = job.exclude.iter().map(|x| x.as_str()).collect();
+    let bandwidth: Option<&str> = job.bwlimit.as_ref().map(|x| x.text.as_str());
+    // --trash は DIR の日付のフォルダ, 値が無ければ freedesktop のゴミ箱
+    let trash: Option<Trash> = job
+        .trash
+        .as_ref()
+        .map(|x| Trash::new((!x.is_empty()).then_some(x.as_str()), _input, _output));
+    let dedupe: Option<Dedupe> = job.dedupe.map(|x| Dedupe::new(x.method(), _output));
+    let versions: Option<Versions> = job
+        .versions
+        .map(|x| Versions::new(x.style(), _output, job.keep_versions, job.keep_days));
     // \\?\D:\foo
     // Windowsは「\\?\」で始まるパスは解釈処理をせず、そのまま扱う
+    // 別のドライブへの rename はファイルごとに copy + verify + delete になる
     let i_drv: String = get_drive(input);
     let o_drv: String = get_drive(output);
 
+    let events: Events = match &job.json {
+        None => Events::none(),
+        Some(path) => match Events::open(path) {
+            Err(e) => cli::fail(ErrorKind::Io, format!("json: {:?} {}", path, e)),
+            Ok(x) => x,
+        },
+    };
+    if let Some(path) = &job.log {
+        if let Err(e) = logger::init(path, &job.log_level) {
+            cli::fail(ErrorKind::Io, format!("log: {:?} {}", path, e));
+        }
+    }
+    log::info!(
+        "start: {} -> {} {} {}",
+        __input,
+        __output,
+        cmr_name,
+        algoname
+    );
     let _start_time: DateTime<Local> = Local::now();
-    println!("{}", _start_time);
-    println!(" {}: [{}] {}", iomod::blue("Input Folder"), i_drv, __input);
-    println!("{}: [{}] {}", iomod::blue("Output Folder"), o_drv, __output);
-    print!("{}: {}, ", iomod::blue("Mode"), cmr_name);
-    print!("{}: {}, ", iomod::blue("Queue"), queue);
-    print!("{}: -{}, ", iomod::blue("Threads"), threads);
-    print!("{}: +{}, ", iomod::blue("Capacity"), capa);
-    println!("{}: {}", iomod::blue("Algorithm"), algoname);
-    if cmr_mode == files::_RENAME && i_drv != o_drv {
-        let message = iomod::red("別のドライブには移動できません".to_string());
-        panic!("{}", message);
+    if !events.is_stdout() {
+        println!("{}", _start_time);
+        println!(
+            " {}: [{}] {}",
+            iomod::blue(tr("input_folder")),
+            i_drv,
+            __input
+        );
+        println!(
+            "{}: [{}] {}",
+            iomod::blue(tr("output_folder")),
+            o_drv,
+            __output
+        );
+        if let Some(x) = &extractor {
+            println!("{}: {}", iomod::blue(tr("archive_entries")), x.name());
+        }
+        print!("{}: {}, ", iomod::blue(tr("mode")), cmr_name);
+        print!("{}: {}, ", iomod::blue(tr("queue")), queue);
+        if threads[thmod::LARGE] > 0 {
+            let large = format!("{}/{}", threads[thmod::SMALL], threads[thmod::LARGE]);
+            print!("{}: {}, ", iomod::blue(tr("threads")), large);
+        } else {
+            print!(
+                "{}: {}, ",
+                iomod::blue(tr("threads")),
+                threads[thmod::SMALL]
+            );
+        }
+        print!("{}: {}, ", iomod::blue(tr("capacity")), capa);
+        print!("{}: {}", iomod::blue(tr("algorithm")), algoname);
+        println!("{}", if job.verify { ", --verify" } else { "" });
+        if let Some(bw) = bandwidth {
+            println!("{}: {}", iomod::blue(tr("bandwidth")), bw);
+        }
+        if !job.devices.is_empty() {
+            let limits: Vec<String> = job
+                .devices
+                .iter()
+                .map(|(m, n)| format!("{}={}", m, n))
+                .collect();
+            println!("{}: {}", iomod::blue(tr("devices")), limits.join(", "));
+        }
+        if !filters.is_empty() {
+            println!("{}: {}", iomod::blue(tr("exclude")), filters.join(" "));
+        }
+        if let Some(x) = &snapshot {
+            let previous: String = x.previous().unwrap_or("-".to_string());
+            println!("{}: {}", iomod::blue(tr("snapshot")), previous);
+        }
+        if let Some(x) = &dedupe {
+            println!("{}: {}", iomod::blue(tr("dedupe")), x.name());
+        }
+        if let Some(x) = &versions {
+            println!("{}: {}", iomod::blue(tr("versions")), x.name());
+        }
+        if let Some(x) = &trash {
+            println!("{}: {}", iomod::blue(tr("quarantine")), x.name());
+        }
     }
+    let options = json!({
+        "input": __input,
+        "output": __output,
+        "mode": cmr_name,
+        "queue": queue,
+        "threads": threads,
+        "capacity": capa,
+        "algorithm": algoname,
+        "bandwidth": bandwidth,
+        "filters": filters,
+        "verify": job.verify,
+        "archive": extractor.as_ref().map(|x| x.name()),
+        "snapshot": snapshot.as_ref().map(|x| x.previous()),
+        "dedupe": dedupe.as_ref().map(|x| x.name()),
+        "versions": versions.as_ref().map(|x| x.name()),
+        "trash": trash.as_ref().map(|x| x.name()),
+    });
+    events.emit("job_started", options.clone());
     let ee = EE {
-        cmr_mode,  // copy, move, rename
-        algorithm, // algorithm
+        cmr_mode,                        // copy, move, rename
+        algorithm: job.algorithm.code(), // algorithm
+    };
+    let mut sched = Scheduler::new(job.queue.policy(), capa, threads);
+    for (mount, limit) in &job.devices {
+        // 空のマウントポイントは全デバイスの既定値
+        let device: Option<u64> = if mount.is_empty() {
+            None
+        } else {
+            match iomod::device_id(mount) {
+                Ok(x) => Some(x),
+                Err(e) => cli::fail(ErrorKind::ValueValidation, format!("{:?} {}", mount, e)),
+            }
+        };
+        sched.set_device_limit(device, *limit);
+    }
+    if let Some(bw) = &job.bwlimit {
+        sched.set_throttle(Throttle::new(bw.rate, bw.hours));
+    }
+    sched.set_events(events);
+    if let Some(path) = &job.report {
+        sched.set_report(Report::new(path, options));
+    }
+    let features = Features {
+        filters: job.exclude.clone(),
+        verify: job.verify,
+        manifest: job.manifest.as_ref().map(|x| Manifest::new(x, _output)),
+        trash,
+        versions,
+        snapshot,
+        dedupe,
+        extractor,
+        ..Features::default()
     };
-    thmod::initialize(fifo, capa);
-    (_input.to_string(), _output.to_string(), ee, threads)
+    let sched = Arc::new(sched);
+    let features = Arc::new(features);
+    (_input.to_string(), _output.to_string(), ee, sched, features)
 }
 
 /**
  * main
  */
 fn main() {
-    let (input, output, ee, threads) = initialize();
-    let _ = files::search_fils(&input, &output, ee); // リクエストを投げる
-    thmod::terminator();
-    daemon::set_threads(threads);
-    daemon::main(); // スレッド起動
-    // async {
-    //     daemon::waiting_for_completion().await; // 完了待ち
-    // };
-    // async {
-    //     daemon::joinall().await;
-    // };
-    println!();
+    let cli: Cli = cli::parse();
+    messages::init(cli.lang.as_deref());
+    match cli.command {
+        Command::Copy(job) => job_main(&job, files::_COPY),
+        Command::Move(job) => job_main(&job, files::_MOVE),
+        Command::Rename(job) => job_main(&job, files::_RENAME),
+        Command::Verify { manifest, dir } => {
+            std::process::exit(manifest::check(&manifest, dir.as_deref()));
+        }
+        Command::Purge { dir, older_than } => {
+            std::process::exit(trash::purge(dir.as_deref(), older_than));
+        }
+        Command::Tar(args) => archive::main(&args),
+        Command::Dupes(args) => dupes::main(&args),
+        Command::Bench(args) => bench::main(&args),
+        Command::Completions { shell } => {
+            let mut command = Cli::command();
+            clap_complete::generate(shell, &mut command, "copy-asyncstd", &mut std::io::stdout());
+        }
+        Command::Run { .. } => unreachable!(), // cli::parse が展開する
+    }
+}
+
+// copy, move, rename
+fn job_main(job: &Job, cmr_mode: char) {
+    let (input, output, ee, sched, features) = initialize(job, cmr_mode);
+    daemon::main(&sched, &features, &input, &output, ee); // 走査とスレッドの起動
+    files::archive_symlinks(&output, &sched, &features); // アーカイブのシンボリックリンク
+    if !sched.events().is_stdout() {
+        println!();
+    }
     if ee.cmr_mode != files::_COPY {
-        // 移動済みの入力フォルダをファイルを含めてまるごと削除
-        iomod::remove_dir_all(&input);
+        // 空になったサブフォルダだけを削除する
+        // (失敗, スキップ, 除外したファイルとそのフォルダ, 入力フォルダ自身は残す)
+        let removed: usize = iomod::remove_empty_dirs(&input);
+        log::info!("delete folders: {} {}", removed, input);
+    }
+    if let Some(manifest) = &features.manifest {
+        if let Err(e) = manifest.write() {
+            sched.error(format!("{}: {}", iomod::red(tr("manifest")), e));
+        }
+    }
+    // 機能ごとの合計 (--dedupe の節約量)
+    let mut notes: Vec<String> = Vec::new();
+    let mut totals = serde_json::Map::new();
+    if let Some(dedupe) = &features.dedupe {
+        let (linked, saved) = dedupe.saved();
+        log::info!("dedupe: {} files, {} bytes saved", linked, saved);
+        let mb: String = format!("{:.1}", saved as f64 / thmod::MB);
+        notes.push(trf("dedupe_saved", &[&linked, &mb]));
+        totals.insert("linked".to_string(), json!(linked));
+        totals.insert("saved_bytes".to_string(), json!(saved));
     }
-    thmod::progress_fin("Finished");
+    sched.progress_fin(tr("finished"), &notes);
+    sched.finished(totals);
     run();
 }
 
@@ -225,7 +421,7 @@ fn _strings() {
     println!("mut String {:?}", _d);
     assert_eq!(_d, "ABCdef");
 
-    let _ch1: char = _a.chars().nth(0).unwrap();
+    let _ch1: char = _a.chars().next().unwrap();
     let _ch2: &str = &_a[..3];
 
     // .contains()
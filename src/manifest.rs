@@ -0,0 +1,191 @@
+/**
+ * Copyright (C) 2023 awk4j - https://ja.osdn.net/projects/awk4j/
+ * <p>
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ * <p>
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ * <p>
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+use chrono::{DateTime, Local};
+use serde_json::{json, Value};
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Error, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::files;
+use crate::files::DD;
+use crate::iomod;
+use crate::messages::{tr, trf};
+
+/**
+ * Manifest - 出力ファイルの一覧 (JSON Lines) (--manifest=<file>)
+ *
+ * 1ファイル1行で、出力フォルダからの相対パス, サイズ, 更新日時, アクション,
+ * コピー中に計算した BLAKE3 ハッシュを書き出す (パスの順に並べる)
+ * verify <file> [<dir>] で、入力フォルダなしに出力フォルダを検証できる
+ */
+pub struct Manifest {
+    path: String,                         // manifest file
+    root: PathBuf,                        // 出力フォルダ
+    entries: Mutex<Vec<(String, Value)>>, // 相対パス, 1行
+}
+
+impl Manifest {
+    pub fn new(path: &str, root: &str) -> Manifest {
+        Manifest {
+            path: path.to_string(),
+            root: PathBuf::from(root),
+            entries: Mutex::new(Vec::new()),
+        }
+    }
+
+    // 完了した出力ファイルを記録する
+    pub fn record(&self, dd: &DD, hash: &str) {
+        let output: &Path = Path::new(&dd.output);
+        let relative: String =
+            iomod::path_to_unix(output.strip_prefix(&self.root).unwrap_or(output));
+        let (size, mtime) = match fs::metadata(output) {
+            Ok(meta) => (
+                meta.len(),
+                meta.modified()
+                    .map(|x| DateTime::<Local>::from(x).to_rfc3339())
+                    .ok(),
+            ),
+            Err(_) => (dd.size, None),
+        };
+        let line: Value = json!({
+            "path": relative,
+            "size": size,
+            "mtime": mtime,
+            "action": files::action_name(dd),
+            "blake3": hash,
+        });
+        self.entries.lock().unwrap().push((relative, line));
+    }
+
+    // パスの順に書き出す
+    pub fn write(&self) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut out = BufWriter::new(File::create(&self.path)?);
+        for (_, line) in entries.iter() {
+            writeln!(out, "{}", line)?;
+        }
+        out.flush()
+    }
+}
+
+/**
+ * check - マニフェストだけで出力フォルダを検証する -> 終了コード
+ *
+ * dir を省略したときはマニフェストのあるフォルダを検証する
+ * 存在しない, サイズが異なる, ハッシュが異なるファイルを表示する
+ */
+pub fn check(path: &str, dir: Option<&str>) -> i32 {
+    let root: PathBuf = match dir {
+        Some(x) => PathBuf::from(x),
+        None => Path::new(path)
+            .parent()
+            .unwrap_or(Path::new("."))
+            .to_path_buf(),
+    };
+    match verify(path, &root) {
+        Ok((ok, bad)) => {
+            let status: String = trf("check_status", &[&ok, &bad]);
+            if bad == 0 {
+                println!("{}: {}", iomod::cyan(tr("check")), status);
+                0
+            } else {
+                println!("{}: {}", iomod::red(tr("check")), status);
+                1
+            }
+        }
+        Err(e) => {
+            eprintln!("{}: {:?} {}", iomod::red(tr("manifest")), path, e);
+            2
+        }
+    }
+}
+
+// 1行ずつ検証する -> (一致, 不一致)
+fn verify(path: &str, root: &Path) -> Result<(usize, usize)> {
+    let (mut ok, mut bad) = (0, 0);
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line: String = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: Value = serde_json::from_str(&line).map_err(Error::other)?;
+        let relative: &str = entry["path"].as_str().unwrap_or_default();
+        match check_entry(&root.join(relative), &entry) {
+            None => ok += 1,
+            Some(problem) => {
+                println!("{} {}", iomod::red(tr(problem)), relative);
+                bad += 1;
+            }
+        }
+    }
+    Ok((ok, bad))
+}
+
+// 1ファイルの検証 -> 問題 (messages の key, None: 一致)
+fn check_entry(file: &Path, entry: &Value) -> Option<&'static str> {
+    let Ok(meta) = fs::metadata(file) else {
+        return Some("missing");
+    };
+    if Some(meta.len()) != entry["size"].as_u64() {
+        return Some("size");
+    }
+    let mut hasher = blake3::Hasher::new();
+    let hashed = File::open(file).and_then(|x| hasher.update_reader(x).map(|_| ()));
+    if hashed.is_err() {
+        return Some("unreadable");
+    }
+    if Some(hasher.finalize().to_hex().as_str()) != entry["blake3"].as_str() {
+        return Some("hash");
+    }
+    None
+}
+
+#[cfg(test)]
+#[test]
+fn manifest_test() {
+    let root = iomod::TempDir::new("manifest_test");
+    fs::create_dir_all(root.join("a")).unwrap();
+    let output: PathBuf = root.join("a").join("x.txt");
+    fs::write(&output, b"hello").unwrap();
+    let dd = DD {
+        input: "in/a/x.txt".to_string(),
+        output: iomod::path_to_string(&output),
+        size: 5,
+        src_dev: 1,
+        dst_dev: 2,
+        action: files::DO,
+        reason: "new",
+        cmr_mode: files::_COPY,
+        algorithm: files::_STD,
+    };
+    let path: String = iomod::path_to_string(root.join("manifest.jsonl"));
+    let manifest = Manifest::new(&path, &iomod::path_to_string(&root));
+    manifest.record(&dd, &blake3::hash(b"hello").to_hex());
+    manifest.write().unwrap();
+    assert!(fs::read_to_string(&path)
+        .unwrap()
+        .contains("\"path\":\"a/x.txt\""));
+    assert_eq!((1, 0), verify(&path, &root).unwrap());
+
+    fs::write(&output, b"HELLO").unwrap(); // 同じサイズで内容を変える
+    assert_eq!((0, 1), verify(&path, &root).unwrap());
+    fs::remove_file(&output).unwrap();
+    assert_eq!((0, 1), verify(&path, &root).unwrap());
+}
@@ -0,0 +1,225 @@
+/**
+ * Copyright (C) 2023 awk4j - https://ja.osdn.net/projects/awk4j/
+ * <p>
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ * <p>
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ * <p>
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+use std::env;
+use std::fmt::Display;
+use std::sync::OnceLock;
+
+/**
+ * Messages - 表示するメッセージのカタログ (英語, 日本語)
+ *
+ * --lang=<en|ja> が無ければ LC_ALL, LC_MESSAGES, LANG の順に調べ、
+ * "ja" で始まれば日本語、それ以外は英語にする
+ * ログと JSON のイベントは翻訳しない
+ * 言語はプロセスで1つ、最初のジョブの前に init が1回だけ決める (以後は変わらない)
+ */
+pub const EN: i32 = 0;
+pub const JA: i32 = 1;
+
+static LANG: OnceLock<i32> = OnceLock::new(); // init の前は英語
+
+// key, English, 日本語 ("{}" は trf で順に置き換える)
+const MESSAGES: &[(&str, &str, &str)] = &[
+    // エラー
+    (
+        "not_folder",
+        "not a folder: {}",
+        "フォルダではありません: {}",
+    ),
+    (
+        "snapshot_copy",
+        "--snapshot can only be used with copy",
+        "--snapshot は copy でのみ使えます",
+    ),
+    (
+        "archive_copy",
+        "an archive input can only be used with copy",
+        "アーカイブの入力は copy でのみ使えます",
+    ),
+    (
+        "archive_dedupe",
+        "--dedupe cannot be used with an archive input",
+        "アーカイブの入力には --dedupe を使えません",
+    ),
+    ("archive", "archive", "アーカイブ"),
+    (
+        "archive_unsafe",
+        "the name points outside the output folder",
+        "出力フォルダの外を指す名前です",
+    ),
+    (
+        "archive_unsupported",
+        "unsupported entry type: {}",
+        "未対応のエントリーです: {}",
+    ),
+    (
+        "archive_no_symlink",
+        "symbolic links cannot be created on this platform",
+        "この環境ではシンボリックリンクを作れません",
+    ),
+    (
+        "symlink_in_path",
+        "symbolic link in the output path: {}",
+        "出力パスの途中にシンボリックリンクがあります: {}",
+    ),
+    ("failed", "failed", "失敗"),
+    ("search", "search", "走査"),
+    ("delete", "delete", "削除"),
+    ("report", "report", "レポート"),
+    ("manifest", "manifest", "マニフェスト"),
+    ("trash", "trash", "ゴミ箱"),
+    // ヘッダー
+    ("input_folder", "Input Folder", "入力フォルダ"),
+    ("output_folder", "Output Folder", "出力フォルダ"),
+    ("mode", "Mode", "モード"),
+    ("queue", "Queue", "キュー"),
+    ("threads", "Threads", "スレッド"),
+    ("capacity", "Capacity", "キャパシティ"),
+    ("algorithm", "Algorithm", "アルゴリズム"),
+    ("bandwidth", "Bandwidth", "帯域制限"),
+    ("devices", "Devices", "デバイス"),
+    ("exclude", "Exclude", "除外"),
+    ("quarantine", "Trash", "ゴミ箱"),
+    ("versions", "Versions", "世代"),
+    ("snapshot", "Snapshot", "スナップショット"),
+    ("dedupe", "Dedupe", "重複排除"),
+    ("archive_entries", "Archive", "アーカイブ"),
+    // 進捗, 合計
+    ("eta", "ETA", "残り"),
+    ("finished", "Finished", "完了"),
+    (
+        "summary",
+        "{} files, {} skipped, {} failed",
+        "{} ファイル, スキップ {}, 失敗 {}",
+    ),
+    (
+        "bench",
+        "{} files, {}MB, {}s, {}MB/s",
+        "{} ファイル, {}MB, {}秒, {}MB/s",
+    ),
+    (
+        "dedupe_saved",
+        "{} duplicates linked, {}MB saved",
+        "重複 {} ファイルをリンク, {}MB 節約",
+    ),
+    // tar
+    ("tar_done", "{} entries, {}MB", "{} エントリー, {}MB"),
+    // dupes
+    ("dupes_group", "{} x {} ({} wasted)", "{} x {} (無駄 {})"),
+    (
+        "dupes_total",
+        "{} groups, {} duplicates, {}MB wasted",
+        "{} グループ, 重複 {} ファイル, 無駄 {}MB",
+    ),
+    (
+        "dupes_linked",
+        "{} duplicates replaced with hard links",
+        "重複 {} ファイルをハードリンクに置き換えました",
+    ),
+    (
+        "dupes_changed",
+        "changed after hashing, not linked",
+        "ハッシュの計算後に変更されたのでリンクしません",
+    ),
+    // verify
+    ("check", "Check", "検証"),
+    ("check_status", "{} ok, {} failed", "一致 {}, 不一致 {}"),
+    ("missing", "missing", "ありません"),
+    ("size", "size differs", "サイズが異なる"),
+    ("hash", "hash differs", "ハッシュが異なる"),
+    ("unreadable", "unreadable", "読めません"),
+    // purge
+    ("purged", "{} entries purged", "{} 件を削除しました"),
+];
+
+// 言語を決める (option: --lang)
+pub fn init(option: Option<&str>) {
+    let lang: i32 = match option {
+        Some(x) => detect(Some(x)),
+        None => {
+            // 空の変数は未設定とみなす (POSIX の優先順位)
+            let locale: Option<String> = ["LC_ALL", "LC_MESSAGES", "LANG"]
+                .iter()
+                .filter_map(|x| env::var(x).ok())
+                .find(|x| !x.is_empty());
+            detect(locale.as_deref())
+        }
+    };
+    let _ = LANG.set(lang);
+}
+
+// ロケール名 -> 言語 (ja_JP.UTF-8 -> JA)
+fn detect(locale: Option<&str>) -> i32 {
+    match locale {
+        Some(x) if x.to_lowercase().starts_with("ja") => JA,
+        _ => EN,
+    }
+}
+
+// key -> メッセージ (未登録なら key)
+pub fn tr(key: &str) -> &str {
+    let lang: i32 = *LANG.get().unwrap_or(&EN);
+    match MESSAGES.iter().find(|x| x.0 == key) {
+        Some((_, en, ja)) => {
+            if lang == JA {
+                ja
+            } else {
+                en
+            }
+        }
+        None => key,
+    }
+}
+
+// key -> "{}" を順に置き換えたメッセージ
+pub fn trf(key: &str, args: &[&dyn Display]) -> String {
+    let mut result: String = String::new();
+    let mut rest: &str = tr(key);
+    for arg in args {
+        match rest.split_once("{}") {
+            Some((head, tail)) => {
+                result.push_str(head);
+                result.push_str(&arg.to_string());
+                rest = tail;
+            }
+            None => break,
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+#[test]
+fn messages_test() {
+    // 両方の言語で "{}" の数が等しい
+    for (key, en, ja) in MESSAGES {
+        assert_eq!(
+            en.matches("{}").count(),
+            ja.matches("{}").count(),
+            "{}",
+            key
+        );
+    }
+    assert_eq!(JA, detect(Some("ja_JP.UTF-8")));
+    assert_eq!(EN, detect(Some("C")));
+    assert_eq!(EN, detect(None));
+    assert_eq!("unknown_key", tr("unknown_key"));
+    assert_eq!(
+        "3 files, 1 skipped, 0 failed",
+        trf("summary", &[&3, &1, &0])
+    );
+}
@@ -0,0 +1,246 @@
+/**
+ * Copyright (C) 2023 awk4j - https://ja.osdn.net/projects/awk4j/
+ * <p>
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ * <p>
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ * <p>
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::Result;
+use std::sync::Mutex;
+
+use crate::files::DD;
+use crate::iomod;
+
+/**
+ * Report - ジョブの結果を1つの HTML ファイルにまとめる (--report=<file>)
+ *
+ * 入出力フォルダ、オプション、合計、スループットの推移 (SVG)、
+ * 大きいファイル、理由ごとのスキップ、すべてのエラー
+ * 外部の CSS/JS を使わないのでブラウザで開くだけで見られる
+ */
+pub struct Report {
+    path: String,        // report file
+    options: Value,      // job_started と同じ内容
+    data: Mutex<Record>, // 集計
+}
+
+// 集計
+#[derive(Default)]
+struct Record {
+    samples: Vec<(f64, u64)>,    // 経過秒, コピー済みのバイト数
+    largest: Vec<(u64, String)>, // サイズ, input file
+    skipped: BTreeMap<String, (usize, Vec<String>)>, // 理由 → 件数, 例
+    errors: Vec<(String, String)>, // input file, error
+}
+
+const LARGEST: usize = 20; // 大きいファイルの表示数
+const EXAMPLES: usize = 20; // スキップの理由ごとの表示数
+const SAMPLE_SECS: f64 = 1.0; // スループットの計測間隔
+
+impl Report {
+    pub fn new(path: &str, options: Value) -> Report {
+        Report {
+            path: path.to_string(),
+            options,
+            data: Mutex::new(Record::default()),
+        }
+    }
+
+    // 経過秒とコピー済みのバイト数 (SAMPLE_SECS ごと)
+    pub fn sample(&self, secs: f64, bytes: u64) {
+        let mut data = self.data.lock().unwrap();
+        let last: f64 = data.samples.last().map(|x| x.0).unwrap_or(0.0);
+        if data.samples.is_empty() || secs - last >= SAMPLE_SECS {
+            data.samples.push((secs, bytes));
+        }
+    }
+
+    pub fn completed(&self, dd: &DD, bytes: u64, skipped: bool) {
+        let mut data = self.data.lock().unwrap();
+        if skipped {
+            let entry = data.skipped.entry(dd.reason.to_string()).or_default();
+            entry.0 += 1;
+            if entry.1.len() < EXAMPLES {
+                entry.1.push(iomod::path_to_unix(&dd.input));
+            }
+            return;
+        }
+        data.largest.push((bytes, iomod::path_to_unix(&dd.input)));
+        if data.largest.len() > LARGEST * 2 {
+            data.largest.sort_by_key(|x| std::cmp::Reverse(x.0));
+            data.largest.truncate(LARGEST);
+        }
+    }
+
+    pub fn failed(&self, input: &str, e: &std::io::Error) {
+        let mut data = self.data.lock().unwrap();
+        data.errors
+            .push((iomod::path_to_unix(input), e.to_string()));
+    }
+
+    // HTML を書き出す
+    pub fn write(&self, totals: &Value) -> Result<()> {
+        let mut data = self.data.lock().unwrap();
+        data.largest.sort_by_key(|x| std::cmp::Reverse(x.0));
+        data.largest.truncate(LARGEST);
+        let mut html = String::new();
+        html.push_str(HEAD);
+        let _ = writeln!(html, "<h1>copy-asyncstd report</h1>");
+        let _ = writeln!(
+            html,
+            "<p>{}</p>",
+            escape(&chrono::Local::now().to_rfc2822())
+        );
+
+        let _ = writeln!(html, "<h2>Job</h2>\n<table>");
+        table_rows(&mut html, &self.options);
+        let _ = writeln!(html, "</table>\n<h2>Totals</h2>\n<table>");
+        table_rows(&mut html, totals);
+        let _ = writeln!(html, "</table>");
+
+        let _ = writeln!(html, "<h2>Throughput</h2>");
+        html.push_str(&chart(&data.samples));
+
+        let _ = writeln!(html, "<h2>Largest files</h2>\n<table>");
+        for (size, input) in &data.largest {
+            let _ = writeln!(
+                html,
+                "<tr><td class=\"num\">{}</td><td>{}</td></tr>",
+                mb(*size),
+                escape(input)
+            );
+        }
+        let _ = writeln!(html, "</table>");
+
+        let _ = writeln!(html, "<h2>Skipped files</h2>\n<table>");
+        for (reason, (count, examples)) in &data.skipped {
+            let _ = writeln!(
+                html,
+                "<tr><th>{}</th><td class=\"num\">{}</td><td>{}</td></tr>",
+                escape(reason),
+                count,
+                examples
+                    .iter()
+                    .map(|x| escape(x))
+                    .collect::<Vec<_>>()
+                    .join("<br>")
+            );
+        }
+        let _ = writeln!(html, "</table>");
+
+        let _ = writeln!(html, "<h2>Errors ({})</h2>\n<table>", data.errors.len());
+        for (input, error) in &data.errors {
+            let _ = writeln!(
+                html,
+                "<tr><td>{}</td><td class=\"err\">{}</td></tr>",
+                escape(input),
+                escape(error)
+            );
+        }
+        let _ = writeln!(html, "</table>\n</body>\n</html>");
+        fs::write(&self.path, html)
+    }
+}
+
+const HEAD: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="UTF-8">
+<title>copy-asyncstd report</title>
+<style>
+body { font-family: sans-serif; margin: 1.5em; }
+table { border-collapse: collapse; margin-bottom: 1em; }
+th, td { border: 1px solid #ccc; padding: 0.2em 0.6em; text-align: left; vertical-align: top; }
+th { background-color: ghostwhite; }
+td.num { text-align: right; }
+td.err { color: #c00; }
+</style>
+</head>
+<body>
+"#;
+
+// JSON のオブジェクトを表の行にする
+fn table_rows(html: &mut String, value: &Value) {
+    if let Value::Object(map) = value {
+        for (key, value) in map {
+            let text: String = match value {
+                Value::String(x) => x.clone(),
+                Value::Null => "-".to_string(),
+                x => x.to_string(),
+            };
+            let _ = writeln!(
+                html,
+                "<tr><th>{}</th><td>{}</td></tr>",
+                escape(key),
+                escape(&text)
+            );
+        }
+    }
+}
+
+// スループット (MB/s) の折れ線グラフ
+fn chart(samples: &[(f64, u64)]) -> String {
+    const W: f64 = 600.0;
+    const H: f64 = 150.0;
+    let rates: Vec<(f64, f64)> = samples
+        .windows(2)
+        .map(|x| {
+            let secs: f64 = (x[1].0 - x[0].0).max(f64::EPSILON);
+            (x[1].0, (x[1].1 - x[0].1) as f64 / 1024.0 / 1024.0 / secs)
+        })
+        .collect();
+    if rates.is_empty() {
+        return "<p>-</p>\n".to_string();
+    }
+    let max_t: f64 = rates.last().unwrap().0.max(f64::EPSILON);
+    let max_r: f64 = rates
+        .iter()
+        .map(|x| x.1)
+        .fold(0.0, f64::max)
+        .max(f64::EPSILON);
+    let points: Vec<String> = rates
+        .iter()
+        .map(|(t, r)| format!("{:.1},{:.1}", t / max_t * W, H - r / max_r * H))
+        .collect();
+    format!(
+        "<svg width=\"{W}\" height=\"{}\" viewBox=\"0 -10 {W} {}\">\n\
+         <polyline fill=\"none\" stroke=\"steelblue\" stroke-width=\"2\" points=\"{}\"/>\n\
+         <text x=\"0\" y=\"0\" font-size=\"12\">{:.1} MB/s</text>\n\
+         <text x=\"{W}\" y=\"{H}\" font-size=\"12\" text-anchor=\"end\">{:.0} s</text>\n</svg>\n",
+        H + 20.0,
+        H + 20.0,
+        points.join(" "),
+        max_r,
+        max_t
+    )
+}
+
+fn mb(size: u64) -> String {
+    format!("{:.1} MB", size as f64 / 1024.0 / 1024.0)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+#[test]
+fn escape_test() {
+    assert_eq!("a &lt;b&gt; &amp; &quot;c&quot;", escape("a <b> & \"c\""));
+}
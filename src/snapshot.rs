@@ -0,0 +1,126 @@
+/**
+ * Copyright (C) 2023 awk4j - https://ja.osdn.net/projects/awk4j/
+ * <p>
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ * <p>
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ * <p>
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+use chrono::{Local, NaiveDateTime};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::iomod;
+
+/**
+ * Snapshot - 日付のフォルダへの差分バックアップ (--snapshot, rsync --link-dest)
+ *
+ * DST/2023-10-01_120000/ のように実行ごとに新しいフォルダへ書き込む
+ * 前回のスナップショット (最も新しい日付のフォルダ) と judgment で比べ、
+ * 変わっていないファイルは前回からハードリンクし、変わったファイルだけをコピーする
+ */
+const STAMP: &str = "%Y-%m-%d_%H%M%S";
+
+pub struct Snapshot {
+    current: PathBuf,          // 今回のスナップショット
+    previous: Option<PathBuf>, // 前回のスナップショット (リンク元)
+}
+
+impl Snapshot {
+    // DST の中に今回のフォルダを決める (作成は initialize が行う)
+    pub fn new(root: &str) -> Snapshot {
+        let root: &Path = Path::new(root);
+        let stamp: String = Local::now().format(STAMP).to_string();
+        let mut name: String = stamp.clone();
+        let previous: Option<PathBuf> = latest(root);
+        // 同じ秒に実行したときは ".1", ".2" を付ける
+        let mut n: u32 = 0;
+        while root.join(&name).exists() {
+            n += 1;
+            name = format!("{}.{}", stamp, n);
+        }
+        Snapshot {
+            current: root.join(name),
+            previous,
+        }
+    }
+
+    pub fn current(&self) -> String {
+        iomod::path_to_string(&self.current)
+    }
+    // 表示用 (unix 形式)
+    pub fn previous(&self) -> Option<String> {
+        self.previous.as_ref().map(iomod::path_to_unix)
+    }
+
+    // 出力ファイル -> 前回のスナップショットの同じファイル
+    pub fn link_source(&self, output: &Path) -> Option<PathBuf> {
+        let previous: &Path = self.previous.as_ref()?;
+        let relative: &Path = output.strip_prefix(&self.current).ok()?;
+        Some(previous.join(relative))
+    }
+}
+
+// 最も新しいスナップショット (STAMP の形式のフォルダ, ".N" 付きを含む)
+fn latest(root: &Path) -> Option<PathBuf> {
+    let mut names: Vec<String> = fs::read_dir(root)
+        .ok()?
+        .filter_map(|x| x.ok())
+        .filter(|x| x.path().is_dir())
+        .map(|x| x.file_name().to_string_lossy().to_string())
+        .filter(|x| is_snapshot(x))
+        .collect();
+    names.sort_by_key(|x| sort_key(x));
+    names.pop().map(|x| root.join(x))
+}
+
+fn is_snapshot(name: &str) -> bool {
+    let stamp: &str = name.split('.').next().unwrap_or_default();
+    NaiveDateTime::parse_from_str(stamp, STAMP).is_ok()
+}
+
+// 同じ秒のスナップショットは ".N" の数値で並べる
+fn sort_key(name: &str) -> (String, u32) {
+    match name.split_once('.') {
+        Some((stamp, n)) => (stamp.to_string(), n.parse().unwrap_or(0)),
+        None => (name.to_string(), 0),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn snapshot_test() {
+    let root = iomod::TempDir::new("snapshot_test");
+    for name in [
+        "2023-10-01_120000",
+        "2023-10-02_120000",
+        "2023-10-02_120000.2",
+        "2023-10-02_120000.10",
+        "music",
+    ] {
+        fs::create_dir_all(root.join(name)).unwrap();
+    }
+    fs::write(root.join("2099-01-01_000000"), "not a folder").unwrap();
+    let snapshot = Snapshot::new(&iomod::path_to_string(&root));
+    assert_eq!(
+        Some(root.join("2023-10-02_120000.10")),
+        snapshot.previous.clone()
+    );
+    fs::create_dir_all(&snapshot.current).unwrap();
+    let output: PathBuf = snapshot.current.join("a/x.txt");
+    assert_eq!(
+        Some(root.join("2023-10-02_120000.10/a/x.txt")),
+        snapshot.link_source(&output)
+    );
+    // 2回目は今回のフォルダが前回になる
+    let next = Snapshot::new(&iomod::path_to_string(&root));
+    assert_eq!(Some(snapshot.current.clone()), next.previous);
+}
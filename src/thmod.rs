@@ -1,6 +1,6 @@
 use regex::Regex;
-use std::collections::VecDeque;
-use std::sync::atomic::AtomicI32;
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
 /**
  * Copyright (C) 2009 awk4j - https://ja.osdn.net/projects/awk4j/
  * <p>
@@ -17,182 +17,650 @@ use std::sync::atomic::AtomicI32;
  * You should have received a copy of the GNU General Public License
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicI32, AtomicU64};
 use std::sync::Mutex;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 // use tokio::sync::Mutex;
 
+use async_std::channel::{Receiver, Sender};
+use async_std::task::JoinHandle;
+
 use crate::atomic;
+use crate::events::Events;
+use crate::files;
 use crate::files::DD;
 use crate::iomod;
+use crate::messages::{tr, trf};
+use crate::report::Report;
+use crate::throttle::Throttle;
 
-// スレッドセーフな STACK の実装
+/**
+ * Scheduler - ジョブ単位のキュー、カウンター、スレッドハンドル
+ *
+ * static mut をやめて、ジョブごとに所有するオブジェクトにまとめた
+ * Arc<Scheduler> を walker と worker に渡すので、
+ * 同一プロセスで複数のジョブを同時に実行できる
+ * (--trash, --dedupe などの機能の状態は features::Features に分ける)
+ *
+ * キューは小さいファイル用 (SMALL) と大きいファイル用 (LARGE) の2レーンで、
+ * レーンごとに worker 数を指定できる (-8/2)
+ * LARGE の worker 数が 0 なら全ファイルを SMALL レーンで処理する
+ * キャパシティ (+N) は2つのレーンの合計
+ *
+ * 入出力のデバイスごとに同時実行数を制限できる (=N, <mount>=N)
+ */
+pub struct Scheduler {
+    lanes: Mutex<[VecDeque<DD>; 2]>,     // QUEUE(FIFO) / STACK(FILO)
+    space: (Sender<()>, Receiver<()>),   // キューの空き (+N 個の許可)
+    policy: char,                        // Scheduling policy
+    threads: [i32; 2],                   // threads number (SMALL, LARGE)
+    counters: Counters,                  // ファイル数とバイト数
+    rate: Mutex<(Instant, u64, f64)>,    // 計測開始時刻, バイト数, MB/s
+    start_time: SystemTime,              // Job start time
+    handles: Mutex<Vec<JoinHandle<()>>>, // スレッドハンドル
+    devices: Mutex<Devices>,             // デバイスごとの同時実行数
+    throttle: Throttle,                  // 帯域制限 (全 worker で共有)
+    slots: Mutex<Vec<Option<Slot>>>,     // worker ごとの実行中のファイル
+    errors: Mutex<VecDeque<String>>,     // 最近のエラー
+    dashboard: bool,                     // 端末なら複数行の表示
+    quiet: bool,                         // 進捗を表示しない (bench)
+    events: Events,                      // JSON Lines (--json)
+    report: Option<Report>,              // HTML report (--report)
+}
 
-lazy_static! { // App start time
-    // https://ytyaru.hatenablog.com/entry/2020/12/15/000000
-    static ref _START_TIME: SystemTime = std::time::SystemTime::now();
+// Counters - ファイル数とバイト数 (worker と walker が更新する)
+#[derive(Default)]
+struct Counters {
+    seq_no: AtomicI32,      // sequence number
+    req_no: AtomicI32,      // current queue number
+    is_eof: AtomicI32,      // Main process ends
+    total_bytes: AtomicU64, // 走査で見つけたバイト数
+    done_bytes: AtomicU64,  // コピー済みのバイト数
+    fail_no: AtomicI32,     // 失敗したファイル数
+    skip_no: AtomicI32,     // スキップしたファイル数
+}
+
+// Devices - デバイスごとの同時実行数 (0: 無制限)
+#[derive(Default)]
+struct Devices {
+    limit: i32,                   // 全デバイスの既定値
+    limits: HashMap<u64, i32>,    // マウントポイントごとの同時実行数
+    in_flight: HashMap<u64, i32>, // デバイスごとの実行中の数
 }
 
-static _SEQ_NO: AtomicI32 = AtomicI32::new(0); // sequence number
-static _REQ_NO: AtomicI32 = AtomicI32::new(0); // current queue number
-static _IS_EOF: AtomicI32 = AtomicI32::new(0); // Main process ends
+impl Devices {
+    // 入力と出力のデバイス (同じなら1つ)
+    fn of(dd: &DD) -> Vec<u64> {
+        if dd.src_dev == dd.dst_dev {
+            vec![dd.src_dev]
+        } else {
+            vec![dd.src_dev, dd.dst_dev]
+        }
+    }
+    // デバイスに空きがあるか
+    fn can_run(&self, dd: &DD) -> bool {
+        Devices::of(dd).iter().all(|dev| {
+            let limit: i32 = *self.limits.get(dev).unwrap_or(&self.limit);
+            limit <= 0 || *self.in_flight.get(dev).unwrap_or(&0) < limit
+        })
+    }
+    // 実行中の数を増減する
+    fn count(&mut self, dd: &DD, n: i32) {
+        for dev in Devices::of(dd) {
+            *self.in_flight.entry(dev).or_insert(0) += n;
+        }
+    }
+}
 
-static _PROGRESS: AtomicI32 = AtomicI32::new(0); // progress lock
-                                                // static mut PRINT: Mutex<i32> = Mutex::new(0); // print lock
-lazy_static! {
-    static ref LOCK: Mutex<i32> = Mutex::new(0); // STACK lock
+// Worker slot - worker が実行中のファイル (dashboard 用)
+#[derive(Debug, Clone)]
+pub struct Slot {
+    pub input: String,  // input file
+    pub size: u64,      // input file length
+    pub done: u64,      // コピー済みのバイト数
+    pub start: Instant, // 開始時刻
 }
-static mut STACK: Vec<DD> = Vec::new(); // Vector
-static mut QUEUE: VecDeque<DD> = VecDeque::new(); // VecDeque
 
-static mut FIFO: bool = true; // FIFO(QUEUE) FILO(STACK)
+impl Scheduler {
+    pub fn new(policy: char, capacity: usize, threads: [i32; 2]) -> Scheduler {
+        Scheduler {
+            lanes: Mutex::new([VecDeque::new(), VecDeque::new()]),
+            space: async_std::channel::bounded(capacity.max(1)), // キャパシティの設定
+            policy,
+            threads,
+            counters: Counters::default(),
+            rate: Mutex::new((Instant::now(), 0, 0.0)),
+            start_time: SystemTime::now(),
+            handles: Mutex::new(Vec::new()),
+            devices: Mutex::new(Devices::default()),
+            throttle: Throttle::new(0, None),
+            slots: Mutex::new(vec![None; (threads[SMALL] + threads[LARGE]) as usize]),
+            errors: Mutex::new(VecDeque::new()),
+            dashboard: std::io::stdout().is_terminal(),
+            quiet: false,
+            events: Events::none(),
+            report: None,
+        }
+    }
+
+    // stdout を JSON にするときはテキストの表示をしない
+    pub fn set_events(&mut self, events: Events) {
+        if events.is_stdout() {
+            self.dashboard = false;
+        }
+        self.events = events;
+    }
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+    pub fn set_quiet(&mut self, quiet: bool) {
+        if quiet {
+            self.dashboard = false;
+        }
+        self.quiet = quiet;
+    }
+    // テキストの表示をしない
+    fn is_quiet(&self) -> bool {
+        self.quiet || self.events.is_stdout()
+    }
+
+    pub fn set_report(&mut self, report: Report) {
+        self.report = Some(report);
+    }
+
+    pub fn set_throttle(&mut self, throttle: Throttle) {
+        self.throttle = throttle;
+    }
+    pub fn throttle(&self) -> &Throttle {
+        &self.throttle
+    }
 
-// static mut MUTEX: OnceCell<Mutex<i32>> = OnceCell::new();
-// static mut ONCE_CELL: OnceCell<Vec<DD>> = OnceCell::new();
+    // デバイスの同時実行数を設定する (None: 全デバイスの既定値)
+    pub fn set_device_limit(&mut self, device: Option<u64>, limit: i32) {
+        let devices: &mut Devices = self.devices.get_mut().unwrap();
+        match device {
+            Some(dev) => {
+                devices.limits.insert(dev, limit);
+            }
+            None => devices.limit = limit,
+        }
+    }
+    // タスクの終了でデバイスを解放する
+    pub fn release(&self, dd: &DD) {
+        self.devices.lock().unwrap().count(dd, -1);
+    }
 
-// スレッド対応の push - by unsafe Rust power
-fn push(dd: DD) -> () {
-    unsafe {
-        let _lock = LOCK.lock();
-        if FIFO {
-            QUEUE.push_back(dd) // 後入れ後出し
+    // ファイルサイズからレーンを決める
+    fn lane_of(&self, dd: &DD) -> usize {
+        if self.threads[LARGE] > 0 && dd.size >= LARGE_FILE {
+            LARGE
         } else {
-            STACK.push(dd) // 後入れ先出し
+            SMALL
         }
     }
-}
-// スレッド対応の pop - by unsafe Rust power
-fn pop() -> Option<DD> {
-    unsafe {
-        let _lock = LOCK.lock();
-        if FIFO {
-            QUEUE.pop_front() // 先入れ先出し
+
+    // スレッド対応の push (put が空きを確保してから呼ぶ)
+    fn push(&self, dd: DD) {
+        let lane: usize = self.lane_of(&dd);
+        self.lanes.lock().unwrap()[lane].push_back(dd); // 後入れ
+    }
+    // スレッド対応の pop
+    // サイズ順・フォルダ順はキューに入っている範囲 (+N) の中で選ぶ
+    // デバイスが上限に達しているリクエストは後回しにする (release で解放)
+    fn pop(&self, lane: usize) -> Option<DD> {
+        let mut lanes = self.lanes.lock().unwrap();
+        let queue: &mut VecDeque<DD> = &mut lanes[lane];
+        let mut devices = self.devices.lock().unwrap();
+        let mut ready = (0..queue.len()).filter(|&i| devices.can_run(&queue[i]));
+        let index: Option<usize> = match self.policy {
+            _LIFO => ready.next_back(),                           // 後入れ先出し
+            _LARGEST => ready.max_by_key(|&i| queue[i].size),     // 大きい順
+            _SMALLEST => ready.min_by_key(|&i| queue[i].size),    // 小さい順
+            _DIRECTORY => ready.min_by_key(|&i| &queue[i].input), // フォルダ順
+            _ => ready.next(),                                    // 先入れ先出し
+        };
+        let dd: DD = queue.remove(index?)?;
+        devices.count(&dd, 1);
+        let _ = self.space.1.try_recv(); // 空きを返す
+        Some(dd)
+    }
+
+    // pop ヘルパー
+    pub fn get(&self, lane: usize) -> Option<DD> {
+        self.pop(lane)
+    }
+    // push ヘルパー - キューが満杯の間は空きができるまで待つ (backpressure)
+    // 空きは容量 +N のチャネルで数え、pop が1つ返すと待っている put が1つ起きる
+    pub async fn put(&self, dd: DD) {
+        // println!("put: {}", dd.input);
+        let _ = self.space.0.send(()).await; // 空きを確保 (満杯なら待つ)
+        self.queued(&dd);
+        atomic::atomic_add(&self.counters.seq_no, 1); // sequence number
+        atomic::atomic_add(&self.counters.req_no, 1); // current queue number
+        if dd.action == files::DO {
+            atomic::atomic_add64(&self.counters.total_bytes, dd.size); // 全体のバイト数 (コピーするファイル)
+        }
+        self.push(dd);
+    }
+
+    // キューに入れたリクエストのイベント
+    fn queued(&self, dd: &DD) {
+        self.events.emit(
+            "file_queued",
+            json!({
+                "input": iomod::path_to_unix(&dd.input),
+                "output": iomod::path_to_unix(&dd.output),
+                "size": dd.size,
+                "action": files::action_name(dd),
+            }),
+        );
+    }
+    // リクエストの完了
+    pub fn completed(&self, dd: &DD, bytes: u64, duration: Duration) {
+        if dd.action == files::SKIP {
+            atomic::atomic_add(&self.counters.skip_no, 1);
+        }
+        if let Some(report) = &self.report {
+            let secs: f64 = self.start_time.elapsed().unwrap_or_default().as_secs_f64();
+            report.completed(dd, bytes, dd.action == files::SKIP);
+            report.sample(secs, atomic::atomic_get64(&self.counters.done_bytes));
+        }
+        let action: &str = files::action_name(dd);
+        log::info!(
+            "{}: {} -> {} ({} bytes)",
+            action,
+            dd.input,
+            dd.output,
+            bytes
+        );
+        self.events.emit(
+            "file_completed",
+            json!({
+                "input": iomod::path_to_unix(&dd.input),
+                "output": iomod::path_to_unix(&dd.output),
+                "bytes": bytes,
+                "duration_ms": duration.as_millis() as u64,
+                "action": files::action_name(dd),
+            }),
+        );
+    }
+    // リクエストの失敗
+    pub fn failed(&self, dd: &DD, e: &std::io::Error) {
+        self.failed_entry(&dd.input, &dd.output, files::action_name(dd), e);
+    }
+    // リクエストにならなかったエントリーの失敗 (アーカイブの読めないエントリーなど)
+    pub fn failed_entry(&self, input: &str, output: &str, action: &str, e: &std::io::Error) {
+        atomic::atomic_add(&self.counters.fail_no, 1);
+        if let Some(report) = &self.report {
+            report.failed(input, e);
+        }
+        self.events.emit(
+            "file_failed",
+            json!({
+                "input": iomod::path_to_unix(input),
+                "output": iomod::path_to_unix(output),
+                "action": action,
+                "error": e.to_string(),
+            }),
+        );
+        self.error(format!("{}: {} {}", iomod::red(tr("failed")), input, e));
+    }
+    // ジョブの完了 - 合計 (extra: 機能ごとの合計, --dedupe の節約量など)
+    pub fn finished(&self, extra: serde_json::Map<String, serde_json::Value>) {
+        let (done, seq) = self.counts();
+        let secs: f64 = self.start_time.elapsed().unwrap_or_default().as_secs_f64();
+        let failed: i32 = atomic::atomic_get(&self.counters.fail_no);
+        let skipped: i32 = atomic::atomic_get(&self.counters.skip_no);
+        let bytes: u64 = atomic::atomic_get64(&self.counters.done_bytes);
+        log::info!(
+            "finished: {} files, {} skipped, {} failed, {} bytes, {}",
+            seq,
+            skipped,
+            failed,
+            bytes,
+            self.elapsed_time()
+        );
+        let mut totals = json!({
+            "files": seq,
+            "completed": done - failed,
+            "skipped": skipped,
+            "failed": failed,
+            "bytes": bytes,
+            "elapsed_ms": (secs * 1000.0) as u64,
+        });
+        for (key, value) in extra {
+            totals[key] = value;
+        }
+        if let Some(report) = &self.report {
+            report.sample(secs, bytes);
+            if let Err(e) = report.write(&totals) {
+                self.error(format!("{}: {}", iomod::red(tr("report")), e));
+            }
+        }
+        self.events.emit("job_finished", totals);
+    }
+
+    // コピーしたバイト数を加算する (チャンクごと) - 帯域制限もここで行う
+    pub async fn transferred(&self, slot: usize, n: usize) {
+        self.throttle.consume(n).await;
+        atomic::atomic_add64(&self.counters.done_bytes, n as u64);
+        if let Some(Some(x)) = self.slots.lock().unwrap().get_mut(slot) {
+            x.done += n as u64;
+        }
+    }
+
+    // worker がファイルの処理を開始・終了する
+    pub fn begin(&self, slot: usize, dd: &DD) {
+        self.events.emit(
+            "file_started",
+            json!({
+                "input": iomod::path_to_unix(&dd.input),
+                "worker": slot,
+            }),
+        );
+        self.slots.lock().unwrap()[slot] = Some(Slot {
+            input: dd.input.clone(),
+            size: dd.size,
+            done: 0,
+            start: Instant::now(),
+        });
+    }
+    pub fn end(&self, slot: usize) {
+        self.slots.lock().unwrap()[slot] = None;
+    }
+    pub fn slots(&self) -> Vec<Option<Slot>> {
+        self.slots.lock().unwrap().clone()
+    }
+
+    // エラーを記録する (dashboard には最近の数件を表示)
+    // エラーは quiet でも stderr に表示する (dashboard は画面に表示する)
+    pub fn error(&self, message: String) {
+        if !self.dashboard {
+            eprintln!("{}", message);
+        }
+        log::error!("{}", message);
+        let mut errors = self.errors.lock().unwrap();
+        errors.push_back(message);
+        if errors.len() > RECENT_ERRORS {
+            errors.pop_front();
+        }
+    }
+    pub fn errors(&self) -> Vec<String> {
+        self.errors.lock().unwrap().iter().cloned().collect()
+    }
+
+    // ファイル数, 失敗したファイル数, コピー済みのバイト数
+    pub fn totals(&self) -> (i32, i32, u64) {
+        (
+            atomic::atomic_get(&self.counters.seq_no),
+            atomic::atomic_get(&self.counters.fail_no),
+            atomic::atomic_get64(&self.counters.done_bytes),
+        )
+    }
+
+    pub fn is_dashboard(&self) -> bool {
+        self.dashboard
+    }
+    // 全体の進捗率 (0.0 - 1.0)
+    pub fn ratio(&self) -> f64 {
+        let total: u64 = atomic::atomic_get64(&self.counters.total_bytes);
+        let done: u64 = atomic::atomic_get64(&self.counters.done_bytes);
+        if total > 0 {
+            (done as f64 / total as f64).min(1.0)
         } else {
-            STACK.pop() // 先入れ後出し
+            1.0
         }
     }
-}
+    // 処理済み/全体のファイル数
+    pub fn counts(&self) -> (i32, i32) {
+        let seq = atomic::atomic_get(&self.counters.seq_no); // sequence number
+        let req = atomic::atomic_get(&self.counters.req_no); // current queue number
+        (seq - req, seq)
+    }
 
-// pop ヘルパー
-pub fn get() -> Option<DD> {
-    let _pop = pop();
-    // match _pop {
-    //     Some(x) =>  Some(x),
-    //     None => None,
-    // }
-    _pop
-}
-// push ヘルパー
-pub fn put(dd: DD) {
-    // println!("put: {}", dd.input);
-    atomic::atomic_add(&_SEQ_NO, 1); // sequence number
-    atomic::atomic_add(&_REQ_NO, 1); // current queue number
-    push(dd);
-}
+    // 現在のスループット (MB/s) - 1秒ごとに計測しなおす
+    fn throughput(&self) -> f64 {
+        let done: u64 = atomic::atomic_get64(&self.counters.done_bytes);
+        let mut rate = self.rate.lock().unwrap();
+        let secs: f64 = rate.0.elapsed().as_secs_f64();
+        if secs >= 1.0 {
+            rate.2 = (done - rate.1) as f64 / secs / MB;
+            *rate = (Instant::now(), done, rate.2);
+        }
+        rate.2
+    }
+    // 進捗率, スループット, 残り時間
+    pub fn bytes_status(&self) -> String {
+        let total: u64 = atomic::atomic_get64(&self.counters.total_bytes);
+        let done: u64 = atomic::atomic_get64(&self.counters.done_bytes);
+        let percent: f64 = self.ratio() * 100.0;
+        let mbps: f64 = self.throughput();
+        let eta: String = if mbps > 0.0 {
+            let secs: f64 = (total - done.min(total)) as f64 / MB / mbps;
+            hms(Duration::from_secs_f64(secs))
+        } else {
+            "-".to_string()
+        };
+        format!("{:.1}% {:.1}MB/s {} {}", percent, mbps, tr("eta"), eta)
+    }
 
-// キューの終了判定
-pub fn is_done() -> bool {
-    let eof: bool = atomic::atomic_bool_get(&_IS_EOF);
-    let req: i32 = atomic::atomic_get(&_REQ_NO);
-    eof && req <= 0
-}
+    // キューの終了判定
+    pub fn is_done(&self) -> bool {
+        let eof: bool = atomic::atomic_bool_get(&self.counters.is_eof);
+        let req: i32 = atomic::atomic_get(&self.counters.req_no);
+        eof && req <= 0
+    }
+    pub fn terminator(&self) {
+        atomic::atomic_bool_set(&self.counters.is_eof, true); // EOF マークの設定
+    }
 
-// ロックを獲得できなければ (!=1) 処理をスキップする
-pub fn progress(input: &String) {
-    // 何も表示されなくなる！ ^^);
-    // if !atomic::atomic_bool_get_set(&_PROGRESS, true) {
-        let seq = atomic::atomic_get(&_SEQ_NO); // sequence number
-        let req = atomic::atomic_add(&_REQ_NO, -1); // 処理要求を減算
-        let ela = elapsed_time(); // 経過時間
-        let cya = iomod::cyan(&format!("{} {}/{}", ela, req, seq));
-        let pack: String = pack_path(input);
-        // \e[nK カーソルより後ろを消去
-        // \e[nA 上にn移動
-        // print!("{}: {}\x1b[K\r", cya, dd.input); // NG
-        // unsafe {
-        // let _print = PRINT.lock().unwrap();
-        println!("{}: {}\x1b[K\x1b[1A", cya, pack);
-        // }
-    // }
-    // atomic::atomic_bool_set(&_PROGRESS, false);
-}
-pub fn progress_fin(message: &str) {
-    let seq = atomic::atomic_get(&_SEQ_NO); // sequence number
-    let req = atomic::atomic_get(&_REQ_NO); // current queue number
-    let ela = elapsed_time(); // 経過時間
-    let cya = iomod::cyan(&format!("{} {}/{}", ela, req, seq));
-    // unsafe {
-    // let _print = PRINT.lock().unwrap();
-    println!("{}: {}", cya, message);
-    // }
-}
-// https://ytyaru.hatenablog.com/entry/2020/12/15/000000
-// as_millis, as_micros, as_nanos
-pub fn elapsed_time() -> String {
-    let now: SystemTime = std::time::SystemTime::now();
-    if let Ok(epoch) = now.duration_since(*_START_TIME) {
-        let hou: u64 = epoch.as_secs() / 3600_u64; // hours >
-        let tmp: u64 = epoch.as_secs() % 3600_u64; // minutes
-        let min: u64 = tmp / 60_u64; // minutes
-        let sec: u64 = tmp % 60_u64; // secs
-        let mil: &str = &format!("{:09}", epoch.as_nanos())[..3];
-        return format!("{}:{}:{:02}.{}", hou, min, sec, mil);
-    }
-    "".to_string() // start > now
+    pub fn threads(&self, lane: usize) -> i32 {
+        self.threads[lane]
+    }
+    // スレッドハンドルを登録
+    pub fn add_handle(&self, handle: JoinHandle<()>) {
+        self.handles.lock().unwrap().push(handle);
+    }
+    // スレッドハンドルを取り出す
+    pub fn take_handle(&self) -> Option<JoinHandle<()>> {
+        self.handles.lock().unwrap().pop()
+    }
+
+    pub fn progress(&self, input: &str) {
+        let seq = atomic::atomic_get(&self.counters.seq_no); // sequence number
+        let req = atomic::atomic_add(&self.counters.req_no, -1); // 処理要求を減算
+        let ela = self.elapsed_time(); // 経過時間
+        let byt = self.bytes_status(); // 進捗率, MB/s, 残り時間
+        if self.dashboard || self.is_quiet() {
+            return; // dashboard が表示する
+        }
+        // 端末でなければ1ファイル1行で出力する
+        let cya = iomod::cyan(format!("{} {}/{} {}", ela, req, seq, byt));
+        println!("{}: {}", cya, iomod::path_to_unix(input));
+    }
+    // notes: 合計の後に表示する行 (機能ごとの合計)
+    pub fn progress_fin(&self, message: &str, notes: &[String]) {
+        let seq = atomic::atomic_get(&self.counters.seq_no); // sequence number
+        let req = atomic::atomic_get(&self.counters.req_no); // current queue number
+        if self.is_quiet() {
+            return;
+        }
+        let ela = self.elapsed_time(); // 経過時間
+        let cya = iomod::cyan(format!("{} {}/{}", ela, req, seq));
+        let done: u64 = atomic::atomic_get64(&self.counters.done_bytes);
+        let secs: f64 = self.start_time.elapsed().unwrap_or_default().as_secs_f64();
+        let mbps: f64 = if secs > 0.0 {
+            done as f64 / MB / secs
+        } else {
+            0.0
+        };
+        let byt = format!("{:.1}MB {:.1}MB/s", done as f64 / MB, mbps); // 平均
+        println!("{}: {} {}", cya, message, iomod::cyan(byt));
+        let failed: i32 = atomic::atomic_get(&self.counters.fail_no);
+        let skipped: i32 = atomic::atomic_get(&self.counters.skip_no);
+        let summary: String = trf("summary", &[&seq, &skipped, &failed]);
+        if failed > 0 {
+            println!("{}", iomod::red(summary));
+        } else {
+            println!("{}", iomod::cyan(summary));
+        }
+        for note in notes {
+            println!("{}", iomod::cyan(note));
+        }
+    }
+    // https://ytyaru.hatenablog.com/entry/2020/12/15/000000
+    pub fn elapsed_time(&self) -> String {
+        let now: SystemTime = std::time::SystemTime::now();
+        if let Ok(epoch) = now.duration_since(self.start_time) {
+            return hms(epoch);
+        }
+        "".to_string() // start > now
+    }
 }
 
+// Lane - 小さいファイルと大きいファイルを別の worker で処理する
+pub const SMALL: usize = 0;
+pub const LARGE: usize = 1;
+pub const LARGE_FILE: u64 = 1024 * 1024; // 1 MiB 以上は LARGE
+
+// Scheduling policy
+pub const _FIFO: char = 'q'; // -q[ueue]
+pub const _LIFO: char = 's'; // -s[tack]
+pub const _LARGEST: char = 'b'; // -b[ig] largest-first
+pub const _SMALLEST: char = 't'; // -t[iny] smallest-first
+pub const _DIRECTORY: char = 'd'; // -d[ir] directory-grouped
+
 const RE_PACK: &str = r"^[^/]+/";
 const PATH_WIDTH: usize = 64; // バイト長
 
+const RECENT_ERRORS: usize = 5; // dashboard に表示するエラーの数
+pub const MB: f64 = 1024.0 * 1024.0;
+
+// as_millis, as_micros, as_nanos
+pub fn hms(epoch: Duration) -> String {
+    let hou: u64 = epoch.as_secs() / 3600_u64; // hours >
+    let tmp: u64 = epoch.as_secs() % 3600_u64; // minutes
+    let min: u64 = tmp / 60_u64; // minutes
+    let sec: u64 = tmp % 60_u64; // secs
+    let mil: &str = &format!("{:09}", epoch.as_nanos())[..3];
+    format!("{}:{}:{:02}.{}", hou, min, sec, mil)
+}
+
 // パスをパック表示する
-fn pack_path(path: &str) -> String {
+pub fn pack_path(path: &str) -> String {
     lazy_static! { // (Regex は一度だけコンパイルされる)
         static ref RE: Regex = Regex::new(RE_PACK).unwrap();
     }
     let mut rs = iomod::path_to_unix(path);
-    while &rs.len() > &PATH_WIDTH && RE.is_match(&rs) {
+    while rs.len() > PATH_WIDTH && RE.is_match(&rs) {
         rs = RE.replace(&rs, "… ").to_string(); // 置換
     }
     rs
 }
 
-pub fn initialize(fifo: bool, _capacity: usize) {
-    let _ = elapsed_time(); // Initialize start time
+#[cfg(test)]
+fn _dd(input: &str, size: u64) -> DD {
+    DD {
+        input: input.to_string(),
+        output: input.to_string(),
+        size,
+        src_dev: 1,
+        dst_dev: 2,
+        action: crate::files::DO,
+        reason: "new",
+        cmr_mode: crate::files::_COPY,
+        algorithm: crate::files::_STD,
+    }
+}
 
-    unsafe {
-        let _lock = LOCK.lock();
-        FIFO = fifo; // First in First out
-        if fifo {
-            // キャパシティの設定
-            QUEUE.reserve_exact(_capacity);
-            // println!("QUEUE_CAPACITY: {}", QUEUE.capacity());
-        } else {
-            STACK.reserve(_capacity);
-            // println!("STACK_CAPACITY: {}", STACK.capacity());
-        }
+#[cfg(test)]
+#[test]
+fn scheduler_test() {
+    // 2つのジョブは互いに干渉しない
+    let queue = Scheduler::new(_FIFO, 4, [1, 0]);
+    let stack = Scheduler::new(_LIFO, 4, [1, 0]);
+    for name in ["a", "b", "c"] {
+        async_std::task::block_on(queue.put(_dd(name, 0)));
+        async_std::task::block_on(stack.put(_dd(name, 0)));
     }
+    assert_eq!("a", queue.get(SMALL).unwrap().input);
+    assert_eq!("c", stack.get(SMALL).unwrap().input);
+    queue.terminator();
+    assert!(!queue.is_done());
+    assert!(!stack.is_done());
 }
-pub fn terminator() {
-    atomic::atomic_bool_set(&_IS_EOF, true); // EOF マークの設定
+
+#[cfg(test)]
+#[test]
+fn capacity_test() {
+    use futures::FutureExt;
+    // キャパシティを超えると put は待たされる
+    let sched = Scheduler::new(_FIFO, 2, [1, 0]);
+    assert!(sched.put(_dd("a", 0)).now_or_never().is_some());
+    assert!(sched.put(_dd("b", 0)).now_or_never().is_some());
+    assert!(sched.put(_dd("c", 0)).now_or_never().is_none());
+    assert_eq!("a", sched.get(SMALL).unwrap().input);
+    assert!(sched.put(_dd("c", 0)).now_or_never().is_some());
+    // キャパシティは2つのレーンの合計
+    let lanes = Scheduler::new(_FIFO, 2, [1, 1]);
+    assert!(lanes.put(_dd("small", 0)).now_or_never().is_some());
+    assert!(lanes.put(_dd("big", LARGE_FILE)).now_or_never().is_some());
+    assert!(lanes.put(_dd("big", LARGE_FILE)).now_or_never().is_none());
+    assert_eq!("small", lanes.get(SMALL).unwrap().input);
+    assert!(lanes.put(_dd("big", LARGE_FILE)).now_or_never().is_some());
 }
 
-//  https://doc.rust-jp.rs/book-ja/ch19-01-unsafe-rust.html
-// unsafe Rustでできること
-// ・可変で静的な変数にアクセスしたり変更すること
-// ・生ポインタを参照外しすること
-// ・unsafeな関数やメソッドを呼ぶこと
-// ・unsafeなトレイトを実装すること
-/*
-Rustにunsafeな分身がある理由は、根本にあるコンピュータのハードウェアが本質的に
-unsafeだからです。Rustがunsafeな処理を行わせてくれなかったら、特定の仕事を行えません。
-Rustは、低レベルなシステムプログラミングを許可する必要があります。
-直接OSと相互作用したり、独自のOSを書くことさえもそうです。
-低レベルなシステムプログラミングに取り組むことは、言語の目標の1つなのです。
-*/
-// 可変で静的な変数を定義
-static mut _TAX: f32 = 0.1;
-fn _test() {
-    unsafe {
-        _TAX = 0.08; // use of mutable static
-        println!("Price: {}", _TAX * 300.0);
+#[cfg(test)]
+#[test]
+fn policy_test() {
+    use async_std::task::block_on;
+    let big = Scheduler::new(_LARGEST, 4, [1, 0]);
+    let tiny = Scheduler::new(_SMALLEST, 4, [1, 0]);
+    let dir = Scheduler::new(_DIRECTORY, 4, [1, 0]);
+    for (name, size) in [("b/2", 20), ("a/1", 30), ("b/1", 10)] {
+        block_on(big.put(_dd(name, size)));
+        block_on(tiny.put(_dd(name, size)));
+        block_on(dir.put(_dd(name, size)));
     }
+    assert_eq!("a/1", big.get(SMALL).unwrap().input);
+    assert_eq!("b/1", tiny.get(SMALL).unwrap().input);
+    assert_eq!("a/1", dir.get(SMALL).unwrap().input);
+    assert_eq!("b/1", dir.get(SMALL).unwrap().input);
+}
+
+#[cfg(test)]
+#[test]
+fn lane_test() {
+    use async_std::task::block_on;
+    let sched = Scheduler::new(_FIFO, 4, [2, 1]);
+    block_on(sched.put(_dd("big", LARGE_FILE)));
+    block_on(sched.put(_dd("small", LARGE_FILE - 1)));
+    assert_eq!("small", sched.get(SMALL).unwrap().input);
+    assert_eq!("big", sched.get(LARGE).unwrap().input);
+    assert!(sched.get(SMALL).is_none());
+}
+
+#[cfg(test)]
+#[test]
+fn device_test() {
+    use async_std::task::block_on;
+    let mut sched = Scheduler::new(_FIFO, 4, [2, 0]);
+    sched.set_device_limit(Some(2), 1); // 出力デバイスは1つずつ
+    block_on(sched.put(_dd("a", 0)));
+    block_on(sched.put(_dd("b", 0)));
+    let a: DD = sched.get(SMALL).unwrap();
+    assert!(sched.get(SMALL).is_none());
+    sched.release(&a);
+    assert_eq!("b", sched.get(SMALL).unwrap().input);
+}
+
+#[cfg(test)]
+#[test]
+fn bytes_test() {
+    use async_std::task::block_on;
+    let sched = Scheduler::new(_FIFO, 4, [1, 0]);
+    block_on(sched.put(_dd("a", 100)));
+    block_on(sched.transferred(0, 50));
+    assert!(sched.bytes_status().starts_with("50.0% "));
 }
@@ -0,0 +1,92 @@
+/**
+ * Copyright (C) 2023 awk4j - https://ja.osdn.net/projects/awk4j/
+ * <p>
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ * <p>
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ * <p>
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+use chrono::{Local, Timelike};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/**
+ * Throttle - 帯域制限 (token bucket)
+ *
+ * 全 worker で共有し、copymax/copych のチャンクごとに consume を呼ぶ
+ * hours を指定すると、その時間帯 (時) だけ制限する (夜間は全速)
+ */
+pub struct Throttle {
+    rate: u64,                     // bytes/sec (0: 無制限)
+    hours: Option<(u32, u32)>,     // 制限する時間帯 [start, end)
+    bucket: Mutex<(f64, Instant)>, // トークン数, 最終補充時刻
+}
+
+impl Throttle {
+    pub fn new(rate: u64, hours: Option<(u32, u32)>) -> Throttle {
+        Throttle {
+            rate,
+            hours,
+            bucket: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    // 現在の時刻で制限が有効か
+    pub fn is_active(&self) -> bool {
+        self.rate > 0 && in_hours(self.hours, Local::now().hour())
+    }
+
+    /**
+     * n バイト分のトークンを消費する
+     *
+     * トークンが足りなければ負債として差し引き、返済できるまでスリープする
+     * (チャンクがバケットより大きくても平均レートを守れる)
+     */
+    pub async fn consume(&self, n: usize) {
+        use async_std::task;
+        if !self.is_active() {
+            return;
+        }
+        let rate: f64 = self.rate as f64;
+        let wait: f64 = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now: Instant = Instant::now();
+            let refill: f64 = now.duration_since(bucket.1).as_secs_f64() * rate;
+            bucket.0 = (bucket.0 + refill).min(rate); // 容量は1秒分
+            bucket.0 -= n as f64;
+            bucket.1 = now;
+            -bucket.0 / rate
+        };
+        if wait > 0.0 {
+            task::sleep(Duration::from_secs_f64(wait)).await;
+        }
+    }
+}
+
+// 時間帯の判定 - start > end は日付をまたぐ (20-8)
+fn in_hours(hours: Option<(u32, u32)>, hour: u32) -> bool {
+    match hours {
+        None => true,
+        Some((start, end)) if start <= end => start <= hour && hour < end,
+        Some((start, end)) => start <= hour || hour < end,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn hours_test() {
+    assert!(in_hours(None, 3));
+    assert!(in_hours(Some((8, 20)), 8));
+    assert!(!in_hours(Some((8, 20)), 20));
+    assert!(in_hours(Some((20, 8)), 23));
+    assert!(in_hours(Some((20, 8)), 7));
+    assert!(!in_hours(Some((20, 8)), 12));
+}
@@ -0,0 +1,345 @@
+/**
+ * Copyright (C) 2023 awk4j - https://ja.osdn.net/projects/awk4j/
+ * <p>
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ * <p>
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ * <p>
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+use chrono::{Local, NaiveDate, NaiveDateTime};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::iomod;
+use crate::messages::{tr, trf};
+
+/**
+ * Trash - 削除・上書きするファイルを隔離する (--trash[=<DIR>])
+ *
+ * DIR を指定すると日付のフォルダに入力・出力フォルダからの相対パスで移す
+ *   <DIR>/2023-10-01/input/a/b.txt   move で削除する入力ファイル
+ *   <DIR>/2023-10-01/output/a/b.txt  上書きする出力ファイル
+ * DIR が無ければ freedesktop のゴミ箱 (~/.local/share/Trash) に移す
+ * 同じ名前があれば ".1", ".2" を付ける
+ * purge で N 日より古いものを削除する (ゴミ箱はこのツールが隔離したものだけ)
+ */
+pub struct Trash {
+    dir: Option<PathBuf>,                   // None: freedesktop のゴミ箱
+    date: String,                           // ジョブの開始日 (YYYY-MM-DD)
+    input: PathBuf,                         // 入力フォルダ
+    output: PathBuf,                        // 出力フォルダ
+    suffixes: Mutex<HashMap<PathBuf, u32>>, // 名前ごとに最後に使った番号
+}
+
+// 隔離するファイルの種類
+pub const INPUT: &str = "input"; // move で削除する入力ファイル
+pub const OUTPUT: &str = "output"; // 上書きする出力ファイル
+
+const DATE: &str = "%Y-%m-%d";
+const TRASH_DATE: &str = "%Y-%m-%dT%H:%M:%S";
+// trashinfo の印 (他のアプリケーションが捨てたものは purge しない)
+const TRASH_MARK: &str = "X-Copy-Asyncstd=true";
+
+impl Trash {
+    pub fn new(dir: Option<&str>, input: &str, output: &str) -> Trash {
+        Trash {
+            dir: dir.map(PathBuf::from),
+            date: Local::now().format(DATE).to_string(),
+            input: PathBuf::from(input),
+            output: PathBuf::from(output),
+            suffixes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // 表示用 (DIR または "freedesktop")
+    pub fn name(&self) -> String {
+        match &self.dir {
+            Some(x) => iomod::path_to_unix(x),
+            None => "freedesktop".to_string(),
+        }
+    }
+
+    // ファイルを隔離する -> 移動先
+    pub fn put(&self, path: &Path, kind: &str) -> Result<PathBuf> {
+        let root: &Path = if kind == INPUT {
+            &self.input
+        } else {
+            &self.output
+        };
+        let relative: &Path = path.strip_prefix(root).unwrap_or(path);
+        let (target, reserved): (PathBuf, PathBuf) = match &self.dir {
+            Some(dir) => {
+                let target: PathBuf = dir.join(&self.date).join(kind).join(relative);
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let target: PathBuf = self.reserve(&target, |x| x.to_path_buf())?;
+                (target.clone(), target) // 空のファイルを rename で置き換える
+            }
+            None => self.freedesktop(path)?,
+        };
+        if let Err(e) = move_file(path, &target) {
+            let _ = fs::remove_file(&reserved); // 予約を取り消す
+            return Err(e);
+        }
+        log::info!("trash: {:?} -> {:?}", path, target);
+        Ok(target)
+    }
+
+    // freedesktop のゴミ箱 - files/<name> と info/<name>.trashinfo -> (移動先, trashinfo)
+    // 仕様どおり trashinfo を O_EXCL で作って名前を予約する
+    fn freedesktop(&self, path: &Path) -> Result<(PathBuf, PathBuf)> {
+        let trash: PathBuf = freedesktop_dir()?;
+        let files_dir: PathBuf = trash.join("files");
+        let info_dir: PathBuf = trash.join("info");
+        fs::create_dir_all(&files_dir)?;
+        fs::create_dir_all(&info_dir)?;
+        let files: PathBuf = files_dir.join(iomod::get_filename(path));
+        let info: PathBuf = self.reserve(&files, |x| {
+            let name: String = iomod::get_filename(x);
+            info_dir.join(format!("{}.trashinfo", name))
+        })?;
+        let name: String = iomod::get_filename(&info);
+        let files: PathBuf = files_dir.join(name.strip_suffix(".trashinfo").unwrap_or(&name));
+        let text: String = format!(
+            "[Trash Info]\nPath={}\nDeletionDate={}\n{}\n",
+            encode(&iomod::absolute_path(path)),
+            Local::now().format(TRASH_DATE),
+            TRASH_MARK
+        );
+        if let Err(e) = fs::write(&info, text) {
+            let _ = fs::remove_file(&info);
+            return Err(e);
+        }
+        Ok((files, info))
+    }
+
+    // 同じ名前があれば ".1", ".2" を付けて reserved(name) を create_new で予約する -> 予約したパス
+    // 前回の番号から探すので、同じ名前が続いても毎回 ".1" から調べない
+    fn reserve<F: Fn(&Path) -> PathBuf>(&self, path: &Path, reserved: F) -> Result<PathBuf> {
+        let start: u32 = self
+            .suffixes
+            .lock()
+            .unwrap()
+            .get(path)
+            .copied()
+            .unwrap_or(0);
+        let (target, n) = reserve(path, start, reserved)?;
+        self.suffixes.lock().unwrap().insert(path.to_path_buf(), n);
+        Ok(target)
+    }
+}
+
+// $XDG_DATA_HOME/Trash (~/.local/share/Trash)
+fn freedesktop_dir() -> Result<PathBuf> {
+    if let Ok(data) = env::var("XDG_DATA_HOME") {
+        if !data.is_empty() {
+            return Ok(Path::new(&data).join("Trash"));
+        }
+    }
+    match env::var("HOME") {
+        Ok(home) => Ok(Path::new(&home).join(".local/share/Trash")),
+        Err(_) => Err(Error::new(ErrorKind::NotFound, "HOME is not set")),
+    }
+}
+
+/**
+ * reserve - 空いている名前を予約する -> (予約したパス, 番号)
+ *
+ * path, path.1, path.2 ... の順に reserved(name) を create_new で作る
+ * exists() で調べてから作ると、同じ名前を隔離する worker どうしで上書きしてしまう
+ * 予約したファイルの横に name が残っているとき (古い隔離ファイル) は次の番号にする
+ */
+pub fn reserve<F: Fn(&Path) -> PathBuf>(
+    path: &Path,
+    start: u32,
+    reserved: F,
+) -> Result<(PathBuf, u32)> {
+    let mut n: u32 = start;
+    loop {
+        let name: PathBuf = if n == 0 {
+            path.to_path_buf()
+        } else {
+            PathBuf::from(format!("{}.{}", iomod::path_to_string(path), n))
+        };
+        let target: PathBuf = reserved(&name);
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&target)
+        {
+            Ok(_) if target != name && name.exists() => {
+                let _ = fs::remove_file(&target); // name だけが残っている
+            }
+            Ok(_) => return Ok((target, n)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(e),
+        }
+        n += 1;
+    }
+}
+
+// rename できなければ (別のファイルシステム) コピーして削除する
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            fs::copy(from, to)?;
+            fs::remove_file(from)
+        }
+        x => x,
+    }
+}
+
+// trashinfo の Path (RFC 2396 のエスケープ, '/' はそのまま)
+fn encode(path: &str) -> String {
+    let mut result: String = String::new();
+    for b in path.bytes() {
+        if b.is_ascii_alphanumeric() || b"/-_.~".contains(&b) {
+            result.push(b as char);
+        } else {
+            result.push_str(&format!("%{:02X}", b));
+        }
+    }
+    result
+}
+
+/**
+ * purge - N 日より古い隔離ファイルを削除する -> 終了コード
+ *
+ * DIR は日付のフォルダ単位、DIR が無ければ freedesktop のゴミ箱の DeletionDate で判定する
+ * ゴミ箱は trashinfo に TRASH_MARK があるもの (--trash が隔離したもの) だけを削除する
+ */
+pub fn purge(dir: Option<&str>, days: u64) -> i32 {
+    let limit: NaiveDateTime = Local::now().naive_local() - chrono::Duration::days(days as i64);
+    let result: Result<usize> = match dir {
+        Some(x) => purge_dir(Path::new(x), limit),
+        None => freedesktop_dir().and_then(|x| purge_freedesktop(&x, limit)),
+    };
+    match result {
+        Ok(n) => {
+            println!("{}: {}", iomod::cyan(tr("trash")), trf("purged", &[&n]));
+            0
+        }
+        Err(e) => {
+            eprintln!("{}: {}", iomod::red(tr("trash")), e);
+            1
+        }
+    }
+}
+
+// 日付のフォルダを削除する -> 削除した数
+fn purge_dir(dir: &Path, limit: NaiveDateTime) -> Result<usize> {
+    let mut purged: usize = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name: String = entry.file_name().to_string_lossy().to_string();
+        let Ok(date) = NaiveDate::parse_from_str(&name, DATE) else {
+            continue; // 日付のフォルダ以外は触らない
+        };
+        // その日の終わりが期限より前なら削除する
+        if date.and_hms_opt(23, 59, 59).unwrap() < limit {
+            fs::remove_dir_all(entry.path())?;
+            log::info!("purge: {:?}", entry.path());
+            purged += 1;
+        }
+    }
+    Ok(purged)
+}
+
+// freedesktop のゴミ箱 -> 削除した数
+fn purge_freedesktop(trash: &Path, limit: NaiveDateTime) -> Result<usize> {
+    let mut purged: usize = 0;
+    let info_dir: PathBuf = trash.join("info");
+    if !info_dir.is_dir() {
+        return Ok(0);
+    }
+    for entry in fs::read_dir(&info_dir)? {
+        let info: PathBuf = entry?.path();
+        let Some(name) = iomod::get_filename(&info)
+            .strip_suffix(".trashinfo")
+            .map(String::from)
+        else {
+            continue;
+        };
+        let text: String = fs::read_to_string(&info).unwrap_or_default();
+        if !text.lines().any(|x| x.trim() == TRASH_MARK) {
+            continue; // 他のアプリケーションが捨てたもの
+        }
+        let date: Option<NaiveDateTime> = text
+            .lines()
+            .find_map(|x| x.strip_prefix("DeletionDate="))
+            .and_then(|x| NaiveDateTime::parse_from_str(x.trim(), TRASH_DATE).ok());
+        if date.is_some_and(|x| x < limit) {
+            let file: PathBuf = trash.join("files").join(&name);
+            if file.is_dir() {
+                fs::remove_dir_all(&file)?;
+            } else if file.exists() {
+                fs::remove_file(&file)?;
+            }
+            fs::remove_file(&info)?;
+            log::info!("purge: {:?}", file);
+            purged += 1;
+        }
+    }
+    Ok(purged)
+}
+
+#[cfg(test)]
+#[test]
+fn trash_test() {
+    let root = iomod::TempDir::new("trash_test");
+    let input: PathBuf = root.join("in");
+    let quarantine: PathBuf = root.join("quarantine");
+    fs::create_dir_all(input.join("a")).unwrap();
+    let trash = Trash::new(
+        Some(&iomod::path_to_string(&quarantine)),
+        &iomod::path_to_string(&input),
+        &iomod::path_to_string(root.join("out")),
+    );
+    for n in 0..2 {
+        fs::write(input.join("a/x.txt"), format!("{}", n)).unwrap();
+        trash.put(&input.join("a/x.txt"), INPUT).unwrap();
+    }
+    let dated: PathBuf = quarantine.join(&trash.date).join(INPUT).join("a");
+    assert_eq!("0", fs::read_to_string(dated.join("x.txt")).unwrap());
+    assert_eq!("1", fs::read_to_string(dated.join("x.txt.1")).unwrap());
+    assert!(!input.join("a/x.txt").exists());
+
+    // 古い日付のフォルダだけを削除する
+    fs::create_dir_all(quarantine.join("2000-01-01/input")).unwrap();
+    fs::create_dir_all(quarantine.join("keep")).unwrap();
+    let limit: NaiveDateTime = Local::now().naive_local() - chrono::Duration::days(30);
+    assert_eq!(1, purge_dir(&quarantine, limit).unwrap());
+    assert!(dated.is_dir());
+    assert!(quarantine.join("keep").is_dir());
+
+    // ゴミ箱はこのツールが隔離したものだけを削除する
+    let bin: PathBuf = root.join("Trash");
+    fs::create_dir_all(bin.join("info")).unwrap();
+    fs::create_dir_all(bin.join("files")).unwrap();
+    for (name, mark) in [("ours", TRASH_MARK), ("theirs", "")] {
+        let text = format!(
+            "[Trash Info]\nPath=/tmp/{}\nDeletionDate=2000-01-01T00:00:00\n{}\n",
+            name, mark
+        );
+        fs::write(bin.join(format!("info/{}.trashinfo", name)), text).unwrap();
+        fs::write(bin.join("files").join(name), name).unwrap();
+    }
+    assert_eq!(1, purge_freedesktop(&bin, limit).unwrap());
+    assert!(!bin.join("files/ours").exists());
+    assert!(bin.join("files/theirs").is_file());
+
+    assert_eq!("/tmp/a%20b/%E3%81%82", encode("/tmp/a b/あ"));
+}
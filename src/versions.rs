@@ -0,0 +1,177 @@
+/**
+ * Copyright (C) 2023 awk4j - https://ja.osdn.net/projects/awk4j/
+ * <p>
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ * <p>
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ * <p>
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+use chrono::{Local, NaiveDateTime};
+use std::fs;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+
+use crate::iomod;
+use crate::trash;
+
+/**
+ * Versions - 上書きする出力ファイルを世代として残す (--versions[=tree|suffix])
+ *
+ * 出力フォルダ DST の a/b.txt を上書きする前に移す
+ *   tree:   DST/.versions/a/b.txt.~20231001-120000~
+ *   suffix: DST/a/b.txt.~20231001-120000~
+ * 時刻はジョブの開始時刻
+ * 移したあとで --keep-versions (新しい順に N 世代) と --keep-days (N 日以内) を超えた世代を削除する
+ */
+pub const _TREE: char = 't';
+pub const _SUFFIX: char = 's';
+
+pub const FOLDER: &str = ".versions";
+const STAMP: &str = "%Y%m%d-%H%M%S";
+
+pub struct Versions {
+    style: char,     // _TREE, _SUFFIX
+    output: PathBuf, // 出力フォルダ
+    stamp: String,   // ジョブの開始時刻
+    keep: usize,     // 残す世代数 (0: 無制限)
+    days: u64,       // 残す日数 (0: 無制限)
+}
+
+impl Versions {
+    pub fn new(style: char, output: &str, keep: usize, days: u64) -> Versions {
+        Versions {
+            style,
+            output: PathBuf::from(output),
+            stamp: Local::now().format(STAMP).to_string(),
+            keep,
+            days,
+        }
+    }
+
+    // 表示用 (tree, suffix と保持の条件)
+    pub fn name(&self) -> String {
+        let mut name: String = if self.style == _TREE {
+            format!("tree ({})", FOLDER)
+        } else {
+            "suffix".to_string()
+        };
+        if self.keep > 0 {
+            name += &format!(", keep {}", self.keep);
+        }
+        if self.days > 0 {
+            name += &format!(", {} days", self.days);
+        }
+        name
+    }
+
+    // 出力ファイルを世代として移す -> 移動先
+    pub fn backup(&self, path: &Path) -> Result<PathBuf> {
+        let folder: PathBuf = self.folder(path);
+        let name: String = format!("{}.~{}~", iomod::get_filename(path), self.stamp);
+        fs::create_dir_all(&folder)?;
+        let (target, _) = trash::reserve(&folder.join(name), 0, |x| x.to_path_buf())?;
+        if let Err(e) = fs::rename(path, &target) {
+            let _ = fs::remove_file(&target); // 予約を取り消す
+            return Err(e);
+        }
+        log::info!("version: {:?} -> {:?}", path, target);
+        self.prune(path)?;
+        Ok(target)
+    }
+
+    // 世代を置くフォルダ
+    fn folder(&self, path: &Path) -> PathBuf {
+        let parent: &Path = path.parent().unwrap_or(Path::new(""));
+        if self.style == _TREE {
+            let relative: &Path = parent.strip_prefix(&self.output).unwrap_or(parent);
+            self.output.join(FOLDER).join(relative)
+        } else {
+            parent.to_path_buf()
+        }
+    }
+
+    // 保持の条件を超えた世代を削除する -> 削除した数
+    fn prune(&self, path: &Path) -> Result<usize> {
+        let mut versions: Vec<(NaiveDateTime, PathBuf)> = self.list(path)?;
+        versions.sort_by(|a, b| b.cmp(a)); // 新しい順 (同じ時刻は ".N" の大きい順)
+        let limit: Option<NaiveDateTime> = (self.days > 0)
+            .then(|| Local::now().naive_local() - chrono::Duration::days(self.days as i64));
+        let mut pruned: usize = 0;
+        for (n, (time, version)) in versions.iter().enumerate() {
+            let over: bool = self.keep > 0 && n >= self.keep;
+            let old: bool = limit.is_some_and(|x| *time < x);
+            if over || old {
+                fs::remove_file(version)?;
+                log::info!("version: delete {:?}", version);
+                pruned += 1;
+            }
+        }
+        Ok(pruned)
+    }
+
+    // ファイルの世代の一覧 (name.~YYYYMMDD-HHMMSS~ と unique の ".N" 付き)
+    fn list(&self, path: &Path) -> Result<Vec<(NaiveDateTime, PathBuf)>> {
+        let prefix: String = format!("{}.~", iomod::get_filename(path));
+        let mut versions: Vec<(NaiveDateTime, PathBuf)> = Vec::new();
+        for entry in fs::read_dir(self.folder(path))? {
+            let entry = entry?;
+            let name: String = entry.file_name().to_string_lossy().to_string();
+            let Some(rest) = name.strip_prefix(&prefix) else {
+                continue;
+            };
+            let stamp: &str = rest.split('~').next().unwrap_or_default();
+            if let Ok(time) = NaiveDateTime::parse_from_str(stamp, STAMP) {
+                versions.push((time, entry.path()));
+            }
+        }
+        Ok(versions)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn versions_test() {
+    let root = iomod::TempDir::new("versions_test");
+    let output: PathBuf = root.join("out");
+    fs::create_dir_all(output.join("a")).unwrap();
+    let file: PathBuf = output.join("a/x.txt");
+    // 古い世代 (2000年) は --keep-days で削除される
+    let tree: PathBuf = output.join(FOLDER).join("a");
+    fs::create_dir_all(&tree).unwrap();
+    fs::write(tree.join("x.txt.~20000101-000000~"), "old").unwrap();
+    fs::write(tree.join("y.txt.~20000101-000000~"), "other").unwrap();
+
+    let versions = Versions::new(_TREE, &iomod::path_to_string(&output), 2, 30);
+    for n in 0..3 {
+        fs::write(&file, format!("{}", n)).unwrap();
+        versions.backup(&file).unwrap();
+    }
+    assert!(!file.exists());
+    let mut names: Vec<String> = fs::read_dir(&tree)
+        .unwrap()
+        .map(|x| x.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    let stamp: &str = &versions.stamp;
+    assert_eq!(
+        vec![
+            format!("x.txt.~{}~.1", stamp),
+            format!("x.txt.~{}~.2", stamp),
+            "y.txt.~20000101-000000~".to_string(), // 別のファイルは残す
+        ],
+        names
+    );
+
+    let suffix = Versions::new(_SUFFIX, &iomod::path_to_string(&output), 0, 0);
+    fs::write(&file, "new").unwrap();
+    let target: PathBuf = suffix.backup(&file).unwrap();
+    assert_eq!(output.join("a"), target.parent().unwrap());
+}